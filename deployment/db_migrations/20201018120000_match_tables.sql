@@ -0,0 +1,43 @@
+-- Bring the schema in line with src/datastore/tables.rs. Databases which were set up by hand from
+-- tables.rs might already have some of these changes, so each one is skipped if it's been made.
+
+-- +goose Up
+-- +goose StatementBegin
+DO $$ BEGIN
+    CREATE TYPE content AS ENUM ('none');
+EXCEPTION
+    WHEN duplicate_object THEN NULL;
+END $$;
+
+ALTER TABLE posts ADD COLUMN IF NOT EXISTS content content NOT NULL DEFAULT 'none';
+
+UPDATE posts SET text = '' WHERE text IS NULL;
+ALTER TABLE posts ALTER COLUMN text DROP DEFAULT;
+ALTER TABLE posts ALTER COLUMN text SET NOT NULL;
+ALTER TABLE posts ALTER COLUMN user_id SET NOT NULL;
+
+-- Each account can only follow another once
+DELETE FROM follows WHERE posts IS NULL OR reads IS NULL;
+DELETE FROM follows a USING follows b
+    WHERE a.ctid < b.ctid AND a.posts = b.posts AND a.reads = b.reads;
+DO $$ BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM pg_constraint WHERE conrelid = 'follows'::regclass AND contype = 'p'
+    ) THEN
+        ALTER TABLE follows ADD PRIMARY KEY (posts, reads);
+    END IF;
+END $$;
+-- +goose StatementEnd
+
+-- +goose Down
+-- +goose StatementBegin
+ALTER TABLE follows DROP CONSTRAINT IF EXISTS follows_pkey;
+ALTER TABLE follows ALTER COLUMN posts DROP NOT NULL;
+ALTER TABLE follows ALTER COLUMN reads DROP NOT NULL;
+
+ALTER TABLE posts ALTER COLUMN user_id DROP NOT NULL;
+ALTER TABLE posts ALTER COLUMN text DROP NOT NULL;
+ALTER TABLE posts ALTER COLUMN text SET DEFAULT NULL;
+ALTER TABLE posts DROP COLUMN IF EXISTS content;
+DROP TYPE IF EXISTS content;
+-- +goose StatementEnd
@@ -1,17 +1,124 @@
-use crate::api::Database;
-use crate::datastore::{postfilters::PostFilters, structs::Post};
-use crate::twoface::Fallible;
-use actix_web::web;
+use crate::api::{
+    query::{compile, Audience, QueryError},
+    Database,
+};
+use crate::datastore::{
+    memory::{Counts, MemoryStore},
+    postfilters::PostFilters,
+    structs::Post,
+    Datastore,
+};
+use crate::logging::{FilterReport, LogHandle};
+use crate::reload::{ReloadOutcome, Reloader};
+use crate::twoface::{Cause, Describe, DescribeErr, ExternalError, Fallible};
+use actix_web::{http::StatusCode, web, HttpResponse};
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::time::Duration;
 
-pub fn configure(cfg: &mut web::ServiceConfig) {
-    cfg.service(web::resource("/posts").route(web::get().to(list_all_posts)));
+/// How long a temporary log filter lasts, unless the request says otherwise.
+const DEFAULT_LOG_FILTER_SECS: u64 = 10 * 60;
+/// Longest a temporary log filter can last.
+const MAX_LOG_FILTER_SECS: u64 = 60 * 60;
+
+pub fn configure<D: Datastore>(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/posts").route(web::get().to(list_all_posts::<D>)))
+        .service(web::resource("/config/reload").route(web::post().to(reload_config)))
+        .service(
+            web::resource("/log_filter")
+                .route(web::get().to(get_log_filter))
+                .route(web::put().to(set_log_filter))
+                .route(web::delete().to(revert_log_filter)),
+        );
+}
+
+/// Endpoints for local development, which are only served when data is stored in memory.
+pub fn configure_dev(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/dev/reset").route(web::post().to(reset_dev_store)));
+}
+
+/// The query language filter, which is parsed separately from the other filters.
+#[derive(Deserialize)]
+struct FilterQuery {
+    filter: Option<String>,
 }
 
 // Admin endpoint
-async fn list_all_posts(
-    state: web::Data<Database>,
+async fn list_all_posts<D: Datastore>(
+    state: web::Data<Database<D>>,
     filters: web::Query<PostFilters>,
+    query: web::Query<FilterQuery>,
 ) -> Fallible<web::Json<Vec<Post>>> {
-    let data = state.ds.list_posts(filters.0).await?;
+    let mut filters = filters.into_inner();
+    filters.check_lists()?;
+    if let Some(filter) = &query.filter {
+        filters.query = Some(compile(filter, Audience::Admin).map_err(QueryError::described)?);
+    }
+    let data = state.ds.list_posts(filters).await?;
     Ok(web::Json(data))
 }
+
+// Admin endpoint
+async fn reload_config(reloader: web::Data<Reloader>) -> Fallible<HttpResponse> {
+    // Reloading reads the config file, so it mustn't block the server's threads
+    let outcome = web::block(move || Ok::<_, ()>(reloader.reload()))
+        .await
+        .map_err(|e| {
+            anyhow!("config reload didn't finish: {}", e).describe(ExternalError {
+                cause: Cause::ServerError,
+                text: "couldn't reload config".into(),
+            })
+        })?;
+    let status = match outcome {
+        ReloadOutcome::Applied { .. } => StatusCode::OK,
+        ReloadOutcome::Rejected { .. } => StatusCode::CONFLICT,
+        ReloadOutcome::Invalid { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+    };
+    Ok(HttpResponse::build(status).json(outcome))
+}
+
+#[derive(Deserialize)]
+struct TemporaryLogFilter {
+    /// `EnvFilter` directives, e.g. `info,onething::datastore=trace`
+    filter: String,
+    /// Seconds until the configured filter is restored
+    revert_after_secs: Option<u64>,
+}
+
+// Admin endpoint
+async fn get_log_filter(logs: web::Data<LogHandle>) -> web::Json<FilterReport> {
+    web::Json(logs.report())
+}
+
+// Admin endpoint
+async fn set_log_filter(
+    logs: web::Data<LogHandle>,
+    body: web::Json<TemporaryLogFilter>,
+) -> Fallible<web::Json<FilterReport>> {
+    let secs = body.revert_after_secs.unwrap_or(DEFAULT_LOG_FILTER_SECS);
+    if secs == 0 || secs > MAX_LOG_FILTER_SECS {
+        return Err(
+            anyhow!("revert_after_secs was {}", secs).describe(ExternalError {
+                cause: Cause::UserInvalidField,
+                text: "revert_after_secs must be between 1 and 3600".into(),
+            }),
+        );
+    }
+    let report = logs
+        .set_temporary_filter(&body.filter, Duration::from_secs(secs))
+        .describe_err(ExternalError {
+            cause: Cause::UserInvalidField,
+            text: "invalid log filter".into(),
+        })?;
+    Ok(web::Json(report))
+}
+
+// Admin endpoint
+async fn revert_log_filter(logs: web::Data<LogHandle>) -> web::Json<FilterReport> {
+    web::Json(logs.revert(None))
+}
+
+// Admin endpoint, for development
+async fn reset_dev_store(state: web::Data<Database<MemoryStore>>) -> web::Json<Counts> {
+    web::Json(state.ds.reset())
+}
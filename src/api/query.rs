@@ -0,0 +1,479 @@
+//! A small query language for filtering posts, e.g.
+//! `text:"release" AND (created>2026-01-01 OR tag:ops) AND NOT deleted`.
+//!
+//! Terms are combined with `AND`, `OR` and `NOT` (in order of increasing precedence) and grouped
+//! with parentheses. A term is a field, optionally followed by `:`, `>` or `<` and a value. Values
+//! with spaces or parentheses must be quoted, and `\"` or `\\` can be used inside quotes.
+use crate::datastore::filter::{Filter, TimeColumn};
+use crate::twoface::{Cause, Describe, ExternalError, TfError};
+use chrono::{offset::Utc, DateTime, NaiveDate};
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while1},
+    character::complete::{char, multispace0, one_of},
+    combinator::{all_consuming, cut, map, opt, verify},
+    error::{context, ErrorKind, ParseError},
+    multi::many0,
+    sequence::{delimited, pair, preceded, terminated},
+    Err, IResult, Offset,
+};
+use std::fmt;
+use uuid::Uuid;
+
+/// Longest filter accepted, in bytes.
+pub const MAX_QUERY_LEN: usize = 1024;
+/// Deepest nesting of parentheses and NOTs accepted. Parsing is recursive, so this stops a
+/// malicious query from overflowing the stack.
+const MAX_DEPTH: usize = 32;
+
+/// Who the filter is for. Some fields are only for admins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Audience {
+    UserFacing,
+    Admin,
+}
+
+/// A parsed filter, before its fields and values are checked.
+#[derive(Debug, PartialEq)]
+pub enum Expr<'a> {
+    And(Vec<Expr<'a>>),
+    Or(Vec<Expr<'a>>),
+    Not(Box<Expr<'a>>),
+    Term(Term<'a>),
+}
+
+/// A field, and what it's compared with, e.g. `created>2026-01-01`. Fields and values are slices of
+/// the query, so that problems with them can be reported with their position.
+#[derive(Debug, PartialEq)]
+pub struct Term<'a> {
+    pub field: &'a str,
+    pub comparison: Option<(Op, Value<'a>)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// `:`
+    Is,
+    /// `>`
+    After,
+    /// `<`
+    Before,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Value<'a> {
+    /// The value as written in the query, including any quotes
+    pub raw: &'a str,
+    /// The value with quotes and escapes removed
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Text,
+    Tag,
+    Id,
+    User,
+    Created,
+    Deleted,
+    Existed,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("text", Field::Text),
+    ("tag", Field::Tag),
+    ("id", Field::Id),
+    ("user", Field::User),
+    ("created", Field::Created),
+    ("deleted", Field::Deleted),
+    ("existed", Field::Existed),
+];
+
+impl Field {
+    fn allowed_for(self, audience: Audience) -> bool {
+        match self {
+            // Users can only query their own posts, so only admins can pick whose posts to see.
+            Field::User => audience == Audience::Admin,
+            _ => true,
+        }
+    }
+
+    /// How the field can be used, for error messages.
+    fn usage(self) -> &'static str {
+        match self {
+            Field::Text => "text:<substring>",
+            Field::Tag => "tag:<tag>",
+            Field::Id => "id:<uuid>",
+            Field::User => "user:<uuid>",
+            Field::Created => "created><time> or created<<time>",
+            Field::Deleted => "deleted, deleted><time> or deleted<<time>",
+            Field::Existed => "existed:<time>",
+        }
+    }
+}
+
+/// A problem with a filter, and where in the filter it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError {
+    /// 1-based, counted in characters
+    pub column: usize,
+    pub message: String,
+}
+
+impl QueryError {
+    /// An error about `at`, which must be a slice of `query`.
+    fn new(query: &str, at: &str, message: String) -> Self {
+        let offset = query.offset(at);
+        Self {
+            column: query[..offset].chars().count() + 1,
+            message,
+        }
+    }
+
+    /// Describe the error to users, including where in the filter it is.
+    pub fn described(self) -> TfError {
+        let text = self.to_string();
+        self.describe(ExternalError {
+            cause: Cause::UserInvalidField,
+            text: text.into(),
+        })
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid filter at column {}: {}",
+            self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// Parse a filter, and check it only uses fields which `audience` may use.
+pub fn compile(query: &str, audience: Audience) -> Result<Filter, QueryError> {
+    if query.len() > MAX_QUERY_LEN {
+        return Err(QueryError {
+            column: 1,
+            message: format!("filters can be at most {} bytes long", MAX_QUERY_LEN),
+        });
+    }
+    let expr = parse(query)?;
+    to_filter(query, &expr, audience)
+}
+
+/// Parse a filter, without checking its fields or values.
+pub fn parse(query: &str) -> Result<Expr<'_>, QueryError> {
+    let whole = all_consuming(terminated(|i| or_expr(i, 0), multispace0));
+    match whole(query) {
+        Ok((_, expr)) => Ok(expr),
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => {
+            let expected = e.expected.unwrap_or("AND, OR or the end of the filter");
+            let found = match e.at.split_whitespace().next() {
+                Some(token) => format!("`{}`", token),
+                None => "the end of the filter".to_owned(),
+            };
+            let message = format!("expected {}, found {}", expected, found);
+            Err(QueryError::new(query, e.at, message))
+        }
+        Err(Err::Incomplete(_)) => unreachable!("only complete parsers are used"),
+    }
+}
+
+/// Where parsing failed, and what was expected there.
+#[derive(Debug, PartialEq)]
+struct SyntaxError<'a> {
+    at: &'a str,
+    expected: Option<&'static str>,
+}
+
+impl<'a> ParseError<&'a str> for SyntaxError<'a> {
+    fn from_error_kind(at: &'a str, _: ErrorKind) -> Self {
+        Self { at, expected: None }
+    }
+
+    fn append(_: &'a str, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    /// Keep the innermost context, since it's the most specific.
+    fn add_context(_: &'a str, ctx: &'static str, mut other: Self) -> Self {
+        other.expected.get_or_insert(ctx);
+        other
+    }
+
+    /// Report whichever alternative got furthest.
+    fn or(self, other: Self) -> Self {
+        if other.at.len() < self.at.len() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+type ParseResult<'a, T> = IResult<&'a str, T, SyntaxError<'a>>;
+
+fn ws<'a, T>(
+    parser: impl Fn(&'a str) -> ParseResult<'a, T>,
+) -> impl Fn(&'a str) -> ParseResult<'a, T> {
+    preceded(multispace0, parser)
+}
+
+/// A keyword, which mustn't run into the following word (so `ANDROID` isn't `AND ROID`).
+fn keyword<'a>(word: &'static str) -> impl Fn(&'a str) -> ParseResult<'a, &'a str> {
+    ws(verify(
+        take_while1(|c: char| c.is_alphanumeric()),
+        move |w: &str| w == word,
+    ))
+}
+
+fn or_expr(input: &str, depth: usize) -> ParseResult<'_, Expr<'_>> {
+    let (input, first) = and_expr(input, depth)?;
+    let (input, rest) = many0(preceded(keyword("OR"), cut(|i| and_expr(i, depth))))(input)?;
+    Ok((input, combine(first, rest, Expr::Or)))
+}
+
+fn and_expr(input: &str, depth: usize) -> ParseResult<'_, Expr<'_>> {
+    let (input, first) = unary(input, depth)?;
+    let (input, rest) = many0(preceded(keyword("AND"), cut(|i| unary(i, depth))))(input)?;
+    Ok((input, combine(first, rest, Expr::And)))
+}
+
+fn combine<'a>(
+    first: Expr<'a>,
+    rest: Vec<Expr<'a>>,
+    op: fn(Vec<Expr<'a>>) -> Expr<'a>,
+) -> Expr<'a> {
+    if rest.is_empty() {
+        first
+    } else {
+        let mut all = vec![first];
+        all.extend(rest);
+        op(all)
+    }
+}
+
+fn unary(input: &str, depth: usize) -> ParseResult<'_, Expr<'_>> {
+    if depth >= MAX_DEPTH {
+        return Err(Err::Failure(SyntaxError {
+            at: input,
+            expected: Some("less nesting"),
+        }));
+    }
+    alt((
+        map(
+            preceded(keyword("NOT"), cut(|i| unary(i, depth + 1))),
+            |e| Expr::Not(Box::new(e)),
+        ),
+        |i| primary(i, depth),
+    ))(input)
+}
+
+fn primary(input: &str, depth: usize) -> ParseResult<'_, Expr<'_>> {
+    context(
+        "a field, `(` or NOT",
+        alt((
+            preceded(
+                ws(char('(')),
+                cut(terminated(
+                    |i| or_expr(i, depth + 1),
+                    context("`)`", ws(char(')'))),
+                )),
+            ),
+            map(term, Expr::Term),
+        )),
+    )(input)
+}
+
+fn term(input: &str) -> ParseResult<'_, Term<'_>> {
+    let field = ws(take_while1(|c: char| c.is_ascii_lowercase() || c == '_'));
+    let op = map(one_of(":<>"), |c| match c {
+        ':' => Op::Is,
+        '>' => Op::After,
+        _ => Op::Before,
+    });
+    let (input, (field, comparison)) =
+        pair(field, opt(pair(op, cut(context("a value", value)))))(input)?;
+    Ok((input, Term { field, comparison }))
+}
+
+fn value(input: &str) -> ParseResult<'_, Value<'_>> {
+    let bare = map(
+        take_while1(|c: char| !c.is_whitespace() && c != '(' && c != ')' && c != '"'),
+        |s: &str| s.to_owned(),
+    );
+    let (rest, text) = alt((quoted, bare))(input)?;
+    let raw = &input[..input.offset(rest)];
+    Ok((rest, Value { raw, text }))
+}
+
+/// A double-quoted string, in which `\"` and `\\` are escapes.
+fn quoted(input: &str) -> ParseResult<'_, String> {
+    let unescaped = take_while1(|c| c != '"' && c != '\\');
+    let escape = preceded(
+        char('\\'),
+        cut(context(
+            "`\"` or `\\` after `\\`",
+            alt((tag("\""), tag("\\"))),
+        )),
+    );
+    let (rest, parts) = delimited(
+        char('"'),
+        many0(alt((unescaped, escape))),
+        cut(context("a closing `\"`", char('"'))),
+    )(input)?;
+    Ok((rest, parts.concat()))
+}
+
+/// Check the filter's fields and values, and turn it into a datastore filter.
+fn to_filter(query: &str, expr: &Expr<'_>, audience: Audience) -> Result<Filter, QueryError> {
+    let all = |exprs: &[Expr<'_>]| -> Result<Vec<Filter>, QueryError> {
+        exprs
+            .iter()
+            .map(|e| to_filter(query, e, audience))
+            .collect()
+    };
+    match expr {
+        Expr::And(exprs) => Ok(Filter::And(all(exprs)?)),
+        Expr::Or(exprs) => Ok(Filter::Or(all(exprs)?)),
+        Expr::Not(expr) => Ok(to_filter(query, expr, audience)?.negate()),
+        Expr::Term(term) => term_to_filter(query, term, audience),
+    }
+}
+
+fn term_to_filter(query: &str, term: &Term<'_>, audience: Audience) -> Result<Filter, QueryError> {
+    let error = |at: &str, message: String| QueryError::new(query, at, message);
+    let field = match FIELDS.iter().find(|(name, _)| *name == term.field) {
+        Some((_, field)) if field.allowed_for(audience) => *field,
+        _ => return Err(error(term.field, format!("unknown field `{}`", term.field))),
+    };
+    let misused = || {
+        let message = format!("`{}` should be used as {}", term.field, field.usage());
+        error(term.field, message)
+    };
+
+    let (op, value) = match &term.comparison {
+        Some((op, value)) => (*op, value),
+        None if field == Field::Deleted => return Ok(Filter::is_deleted(true)),
+        None => return Err(misused()),
+    };
+    let uuid = || {
+        Uuid::parse_str(&value.text)
+            .map_err(|_| error(value.raw, format!("`{}` isn't a valid UUID", value.text)))
+    };
+    let time = || {
+        parse_time(&value.text).ok_or_else(|| {
+            let message = format!(
+                "`{}` isn't a valid time; use YYYY-MM-DD or RFC 3339, e.g. 2026-01-01T12:00:00Z",
+                value.text
+            );
+            error(value.raw, message)
+        })
+    };
+    let filter = match (field, op) {
+        (Field::Text, Op::Is) => Filter::text_contains(&value.text),
+        (Field::Tag, Op::Is) => {
+            let tag = value.text.trim_start_matches('#');
+            if tag.is_empty() || tag.contains(char::is_whitespace) {
+                let message = "tags must be one word, without spaces".to_owned();
+                return Err(error(value.raw, message));
+            }
+            Filter::has_tag(tag)
+        }
+        (Field::Id, Op::Is) => Filter::id(uuid()?),
+        (Field::User, Op::Is) => Filter::user_id(uuid()?),
+        (Field::Created, Op::After) => Filter::after(TimeColumn::CreatedAt, time()?),
+        (Field::Created, Op::Before) => Filter::before(TimeColumn::CreatedAt, time()?),
+        (Field::Deleted, Op::After) => Filter::after(TimeColumn::DeletedAt, time()?),
+        (Field::Deleted, Op::Before) => Filter::before(TimeColumn::DeletedAt, time()?),
+        (Field::Existed, Op::Is) => Filter::existed_at(time()?),
+        _ => return Err(misused()),
+    };
+    Ok(filter)
+}
+
+/// An RFC 3339 time, or a date (meaning midnight UTC at the start of that day).
+fn parse_time(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Some(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+    Some(DateTime::from_utc(date.and_hms(0, 0, 0), Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_compile() {
+        let filter = compile(
+            r#"text:"release" AND (created>2026-01-01 OR tag:ops) AND NOT deleted"#,
+            Audience::UserFacing,
+        )
+        .unwrap();
+        let new_year = Utc.ymd(2026, 1, 1).and_hms(0, 0, 0);
+        assert_eq!(
+            filter,
+            Filter::And(vec![
+                Filter::text_contains("release"),
+                Filter::Or(vec![
+                    Filter::after(TimeColumn::CreatedAt, new_year),
+                    Filter::has_tag("ops"),
+                ]),
+                Filter::is_deleted(true).negate(),
+            ])
+        );
+
+        // NOT binds tighter than AND, which binds tighter than OR
+        let filter = compile(r#"NOT tag:a OR tag:b AND text:"x \"y\"""#, Audience::Admin);
+        assert_eq!(
+            filter.unwrap(),
+            Filter::Or(vec![
+                Filter::has_tag("a").negate(),
+                Filter::And(vec![Filter::has_tag("b"), Filter::text_contains("x \"y\"")]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let user_id = Uuid::nil();
+        let cases = vec![
+            ("text:", 6, "expected a value, found the end of the filter"),
+            ("(deleted OR  ", 14, "expected a field, `(` or NOT, found the end of the filter"),
+            ("(deleted", 9, "expected `)`, found the end of the filter"),
+            ("deleted tag:x", 9, "expected AND, OR or the end of the filter, found `tag:x`"),
+            (r#"text:"unclosed"#, 15, "expected a closing `\"`, found the end of the filter"),
+            ("NOT colour:red", 5, "unknown field `colour`"),
+            ("text", 1, "`text` should be used as text:<substring>"),
+            ("created:2026-01-01", 1, "`created` should be used as created><time> or created<<time>"),
+            ("id:1234", 4, "`1234` isn't a valid UUID"),
+            (r#"text:"café" AND existed:yesterday"#, 25, "`yesterday` isn't a valid time; use YYYY-MM-DD or RFC 3339, e.g. 2026-01-01T12:00:00Z"),
+        ];
+        for (query, column, message) in cases {
+            let error = compile(query, Audience::UserFacing).unwrap_err();
+            assert_eq!(
+                (error.column, &error.message[..]),
+                (column, message),
+                "{}",
+                query
+            );
+        }
+        let query = format!("user:{}", user_id);
+        assert_eq!(
+            compile(&query, Audience::UserFacing).unwrap_err().message,
+            "unknown field `user`"
+        );
+        assert_eq!(
+            compile(&query, Audience::Admin).unwrap(),
+            Filter::user_id(user_id)
+        );
+        let nested = format!("{}deleted{}", "(".repeat(100), ")".repeat(100));
+        assert!(compile(&nested, Audience::Admin).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! Clients see their own writes, even though reads usually go to replicas which might lag.
+//!
+//! Successful writes are answered with a `Quiet-Last-Write` header, holding when the write
+//! happened. Clients send it back with their next requests, and until `read_your_writes_window`
+//! has passed, those requests read from the primary.
+use crate::datastore::postgres::reading_own_writes;
+use crate::reload::LiveSettings;
+use actix_service::Service;
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::{HeaderName, HeaderValue},
+    Error,
+};
+use futures::future::{Either, Future};
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// When the client last wrote, in milliseconds since the Unix epoch.
+pub const LAST_WRITE: &str = "quiet-last-write";
+
+/// Middleware (for `App::wrap_fn`) which stamps successful writes with `Quiet-Last-Write`, and
+/// sends reads from requests with a recent `Quiet-Last-Write` to the primary.
+pub fn read_your_writes<S, B>(
+    request: ServiceRequest,
+    srv: &mut S,
+    settings: &LiveSettings,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let window_ms = settings.read_your_writes_window.load(Ordering::Relaxed) * 1000;
+    let now = now_ms();
+    // Allow for clocks differing between servers, but not so much that the header could keep a
+    // client reading from the primary forever.
+    let wrote_recently = request
+        .headers()
+        .get(LAST_WRITE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .is_some_and(|wrote| now.max(wrote) - now.min(wrote) < window_ms);
+    let writes = !request.method().is_safe();
+
+    let response = if wrote_recently {
+        Either::Left(reading_own_writes(srv.call(request)))
+    } else {
+        Either::Right(srv.call(request))
+    };
+    async move {
+        let mut response = response.await?;
+        if writes && window_ms > 0 && response.status().is_success() {
+            response.headers_mut().insert(
+                HeaderName::from_static(LAST_WRITE),
+                HeaderValue::from(now_ms()),
+            );
+        }
+        Ok(response)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::postgres::must_read_primary;
+    use actix_web::{test, web, App};
+    use std::sync::atomic::{AtomicU64, AtomicUsize};
+    use std::sync::Arc;
+
+    #[actix_rt::test]
+    async fn test_reads_after_writes_use_primary() {
+        let settings = Arc::new(LiveSettings {
+            max_body_size: AtomicUsize::new(1024),
+            read_your_writes_window: AtomicU64::new(5),
+            request_deadline_ms: AtomicU64::new(1000),
+        });
+        let app_settings = Arc::clone(&settings);
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(move |req, srv| read_your_writes(req, srv, &app_settings))
+                .route("/", web::post().to(|| async { "written" }))
+                .route(
+                    "/",
+                    web::get().to(|| async { must_read_primary().to_string() }),
+                ),
+        )
+        .await;
+
+        let resp = test::call_service(&mut app, test::TestRequest::post().to_request()).await;
+        let last_write = resp.headers().get(LAST_WRITE).unwrap().clone();
+        let read = |last_write: Option<HeaderValue>| {
+            let mut req = test::TestRequest::get();
+            if let Some(last_write) = last_write {
+                req = req.header(LAST_WRITE, last_write);
+            }
+            req.to_request()
+        };
+
+        let resp = test::read_response(&mut app, read(Some(last_write.clone()))).await;
+        assert_eq!(resp, "true");
+        let resp = test::read_response(&mut app, read(None)).await;
+        assert_eq!(resp, "false");
+        let long_ago = HeaderValue::from(now_ms() - 60_000);
+        let resp = test::read_response(&mut app, read(Some(long_ago))).await;
+        assert_eq!(resp, "false");
+
+        // With no window, reads can always go to replicas, and writes aren't stamped
+        settings.read_your_writes_window.store(0, Ordering::Relaxed);
+        let resp = test::read_response(&mut app, read(Some(last_write))).await;
+        assert_eq!(resp, "false");
+        let resp = test::call_service(&mut app, test::TestRequest::post().to_request()).await;
+        assert!(resp.headers().get(LAST_WRITE).is_none());
+    }
+}
@@ -1,7 +1,14 @@
 //! For every business-logic struct in `datastore`, this module will have a matching struct
 //! which redacts some business-sensitive fields.
-use crate::api::{observe, AccountPost, CoerceColl, Database};
-use crate::datastore::structs::{Content, NewPost, Post};
+use crate::api::{
+    query::{compile, Audience, QueryError},
+    AccountPost, CoerceColl, Database,
+};
+use crate::datastore::{
+    postfilters,
+    structs::{Content, NewPost, Post},
+    Datastore,
+};
 use crate::twoface::Fallible;
 use actix_web::web;
 
@@ -9,13 +16,13 @@ use chrono::{offset::Utc, DateTime};
 use serde::{self, Deserialize, Serialize};
 use uuid::Uuid;
 
-pub fn configure(cfg: &mut web::ServiceConfig) {
+pub fn configure<D: Datastore>(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/{user_id}/posts")
-            .route("", web::post().to(write_post))
-            .route("", web::get().to(list_posts))
-            .route("/{post_id}", web::get().to(get_post))
-            .route("/{post_id}", web::delete().to(delete_post)),
+            .route("", web::post().to(write_post::<D>))
+            .route("", web::get().to(list_posts::<D>))
+            .route("/{post_id}", web::get().to(get_post::<D>))
+            .route("/{post_id}", web::delete().to(delete_post::<D>)),
     );
 }
 
@@ -49,61 +56,49 @@ pub struct WritePostBody {
 }
 
 // Insert a post into the datastore
-async fn write_post(
-    state: web::Data<Database>,
+async fn write_post<D: Datastore>(
+    state: web::Data<Database<D>>,
     user_id: web::Path<Uuid>,
     body: web::Json<WritePostBody>,
 ) -> Fallible<web::Json<UserFacingPost>> {
-    observe("post_post", || async {
-        let new_post = NewPost {
-            user_id: *user_id,
-            content: body.content,
-            text: body.text.clone(),
-        };
-        let post = state.ds.new_post(new_post).await?;
-        Ok(web::Json(post.into()))
-    })
-    .await
+    let new_post = NewPost {
+        user_id: *user_id,
+        content: body.content,
+        text: body.text.clone(),
+    };
+    let post = state.ds.new_post(new_post).await?;
+    Ok(web::Json(post.into()))
 }
 
 // Get all user's posts from the datastore
-async fn list_posts(
-    state: web::Data<Database>,
+async fn list_posts<D: Datastore>(
+    state: web::Data<Database<D>>,
     user_id: web::Path<Uuid>,
     filters: web::Query<PostFilters>,
 ) -> Fallible<web::Json<Vec<UserFacingPost>>> {
-    observe("list_post", || async {
-        let filters = filters.into_inner().into_datastore_filters(*user_id);
-        let posts_and_conns = state.ds.list_posts(filters).await?.coerce_into();
-        Ok(web::Json(posts_and_conns))
-    })
-    .await
+    let filters = filters.into_inner().into_datastore_filters(*user_id)?;
+    let posts_and_conns = state.ds.list_posts(filters).await?.coerce_into();
+    Ok(web::Json(posts_and_conns))
 }
 
-async fn get_post(
-    state: web::Data<Database>,
+async fn get_post<D: Datastore>(
+    state: web::Data<Database<D>>,
     path: web::Path<AccountPost>,
 ) -> Fallible<web::Json<Option<UserFacingPost>>> {
-    observe("get_post", || async {
-        let post = state.ds.find_post(path.user_id, path.post_id).await?;
-        Ok(web::Json(post.map(UserFacingPost::from)))
-    })
-    .await
+    let post = state.ds.find_post(path.user_id, path.post_id).await?;
+    Ok(web::Json(post.map(UserFacingPost::from)))
 }
 
-async fn delete_post(
-    state: web::Data<Database>,
+async fn delete_post<D: Datastore>(
+    state: web::Data<Database<D>>,
     path: web::Path<AccountPost>,
 ) -> Fallible<web::Json<Option<UserFacingPost>>> {
-    observe("delete_post", || async {
-        let response = state
-            .ds
-            .delete_post(path.user_id, path.post_id)
-            .await?
-            .map(|t| UserFacingPost::from(t));
-        Ok(web::Json(response))
-    })
-    .await
+    let response = state
+        .ds
+        .delete_post(path.user_id, path.post_id)
+        .await?
+        .map(|t| UserFacingPost::from(t));
+    Ok(web::Json(response))
 }
 
 /// Filters that users can specify via the Poststore API
@@ -112,8 +107,17 @@ pub struct PostFilters {
     pub name: Option<String>,
     pub is_deleted: Option<bool>,
     pub existed_at: Option<DateTime<Utc>>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub deleted_after: Option<DateTime<Utc>>,
+    pub deleted_before: Option<DateTime<Utc>>,
     pub uuid: Option<Uuid>,
+    /// Comma-separated post IDs
+    #[serde(default, deserialize_with = "postfilters::comma_separated")]
+    pub ids: Option<Vec<Uuid>>,
     pub text_contains: Option<String>,
+    /// An expression in the query language, see `api::query`
+    pub filter: Option<String>,
     pub limit: u8,
 }
 
@@ -122,17 +126,102 @@ impl PostFilters {
     // Nor should they ever be able to query posts they don't own. Instead, API filters should
     // have to be combined with an account ID (which Poststore extracts from the URL path/user creds)
     // before the datastore can execute them.
-    pub fn into_datastore_filters(
-        self,
-        user_id: Uuid,
-    ) -> crate::datastore::postfilters::PostFilters {
-        crate::datastore::postfilters::PostFilters {
+    pub fn into_datastore_filters(self, user_id: Uuid) -> Fallible<postfilters::PostFilters> {
+        let query = self
+            .filter
+            .as_deref()
+            .map(|filter| compile(filter, Audience::UserFacing))
+            .transpose()
+            .map_err(QueryError::described)?;
+        let filters = postfilters::PostFilters {
             user_id: Some(user_id),
+            user_ids: None,
             is_deleted: self.is_deleted,
             existed_at: self.existed_at,
+            created_after: self.created_after,
+            created_before: self.created_before,
+            deleted_after: self.deleted_after,
+            deleted_before: self.deleted_before,
             text_contains: self.text_contains,
             id: self.uuid,
+            ids: self.ids,
+            query,
             limit: self.limit,
-        }
+        };
+        filters.check_lists()?;
+        Ok(filters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::{memory::MemoryStore, structs::NewUser};
+    use actix_web::{test, App};
+    use std::sync::Arc;
+
+    #[actix_rt::test]
+    async fn test_post_lifecycle() {
+        let store = Arc::new(MemoryStore::default());
+        let new_user = |name: &str| NewUser {
+            name: name.to_owned(),
+        };
+        let author = store.new_user(new_user("author")).await.unwrap().id;
+        let other = store.new_user(new_user("other")).await.unwrap().id;
+        let mut app = test::init_service(
+            App::new()
+                .data(Database { ds: store })
+                .service(web::scope("/accounts").configure(configure::<MemoryStore>)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/accounts/{}/posts", author))
+            .set_json(&WritePostBody {
+                text: "hello".to_owned(),
+                content: Content::None,
+            })
+            .to_request();
+        let written: UserFacingPost = test::read_response_json(&mut app, req).await;
+        assert_eq!(written.text, "hello");
+        let post_id = written.id;
+
+        let list = |user_id: Uuid| {
+            test::TestRequest::get()
+                .uri(&format!("/accounts/{}/posts?limit=10", user_id))
+                .to_request()
+        };
+        let listed: Vec<UserFacingPost> = test::read_response_json(&mut app, list(author)).await;
+        assert_eq!(listed, vec![written]);
+        let listed: Vec<UserFacingPost> = test::read_response_json(&mut app, list(other)).await;
+        assert!(listed.is_empty());
+
+        let search = |filter: &str| {
+            let uri = format!("/accounts/{}/posts?limit=10&filter={}", author, filter);
+            test::TestRequest::get().uri(&uri).to_request()
+        };
+        let req = search("text:hello%20AND%20NOT%20deleted");
+        let listed: Vec<UserFacingPost> = test::read_response_json(&mut app, req).await;
+        assert_eq!(listed.len(), 1);
+        let resp = test::call_service(&mut app, search("text:hello%20AND%20user:x")).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body["error"],
+            "UserInvalidField: invalid filter at column 16: unknown field `user`"
+        );
+
+        let other_post = format!("/accounts/{}/posts/{}", other, post_id);
+        let req = test::TestRequest::delete().uri(&other_post).to_request();
+        let deleted: Option<UserFacingPost> = test::read_response_json(&mut app, req).await;
+        assert_eq!(deleted, None);
+
+        let own_post = format!("/accounts/{}/posts/{}", author, post_id);
+        let req = test::TestRequest::delete().uri(&own_post).to_request();
+        let deleted: Option<UserFacingPost> = test::read_response_json(&mut app, req).await;
+        assert!(deleted.unwrap().deleted_at.is_some());
+        let req = test::TestRequest::get().uri(&own_post).to_request();
+        let found: Option<UserFacingPost> = test::read_response_json(&mut app, req).await;
+        assert!(found.unwrap().deleted_at.is_some());
     }
 }
@@ -0,0 +1,170 @@
+//! One-off commands, which run and exit instead of serving.
+use crate::config::{Command, Config, MigrateCommand, Storage};
+use crate::datastore::{
+    postgres::{self, migrations::Migrator, Dsn, PostgresStore},
+    structs::NewUser,
+    synthetic::{self, Manifest, Output, Spec},
+    Datastore,
+};
+use crate::twoface::TfError;
+use anyhow::{bail, Context};
+use chrono::{offset::Utc, Duration};
+use rand::RngCore;
+use std::future::Future;
+use std::path::Path;
+use tracing::info;
+
+/// Random bytes in a token from `gen-token`.
+const TOKEN_BYTES: usize = 32;
+
+/// Run `command`. Its output goes to stdout, and logs go to stderr.
+pub fn run(command: Command, config: &Config) -> Result<(), anyhow::Error> {
+    match command {
+        Command::Serve | Command::CheckConfig => {
+            unreachable!("{:?} isn't a one-off command", command)
+        }
+        Command::Migrate(command) => migrate(config, command),
+        Command::CreateUser { name } => {
+            let user = with_store(config, |db| async move {
+                db.new_user(NewUser { name }).await.map_err(to_anyhow)
+            })?;
+            println!("{}", serde_json::to_string(&user)?);
+            Ok(())
+        }
+        Command::ExportUser { user_id } => {
+            let export = with_store(config, |db| async move {
+                db.export_user(user_id).await.map_err(to_anyhow)
+            })?;
+            match export {
+                Some(export) => println!("{}", serde_json::to_string_pretty(&export)?),
+                None => bail!("there's no user {}", user_id),
+            }
+            Ok(())
+        }
+        Command::PurgeDeleted { days } => {
+            let before = Utc::now() - Duration::days(days.into());
+            let purged = with_store(config, |db| async move {
+                db.purge_deleted(before).await.map_err(to_anyhow)
+            })?;
+            println!(
+                "Purged {} posts, {} users and {} follows deleted before {}",
+                purged.posts,
+                purged.users,
+                purged.follows,
+                before.to_rfc3339()
+            );
+            Ok(())
+        }
+        Command::GenToken => {
+            let mut bytes = [0; TOKEN_BYTES];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            println!("{}", base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD));
+            Ok(())
+        }
+        Command::Generate { spec } => generate(config, &spec),
+    }
+}
+
+fn generate(config: &Config, spec: &Path) -> Result<(), anyhow::Error> {
+    let spec = Spec::read(spec)?;
+    let snapshot = synthetic::generate(&spec);
+    info!(
+        users = snapshot.users.len(),
+        posts = snapshot.posts.len(),
+        follows = snapshot.follows.len(),
+        "generated data"
+    );
+    let seed = spec.seed;
+    let manifest = match &spec.output {
+        Output::Store => {
+            let concurrency = config.db_pool_size as usize;
+            with_store(config, move |db| async move {
+                synthetic::write_through(&db, snapshot, seed, concurrency)
+                    .await
+                    .map_err(to_anyhow)
+            })?
+        }
+        Output::Bulk => {
+            let manifest = Manifest::new(seed, &snapshot);
+            with_store(config, |db| async move {
+                db.bulk_insert(snapshot).await.map_err(to_anyhow)
+            })?;
+            manifest
+        }
+        Output::Fixtures { file } => {
+            snapshot.write(file)?;
+            Manifest::new(seed, &snapshot)
+        }
+    };
+    if let Some(path) = &spec.manifest_file {
+        std::fs::write(path, serde_json::to_vec_pretty(&manifest)?)
+            .with_context(|| format!("couldn't write {}", path.display()))?;
+    }
+    println!(
+        "Wrote {} users, {} posts and {} follows",
+        manifest.users.len(),
+        manifest.posts.len(),
+        manifest.follows
+    );
+    Ok(())
+}
+
+/// Run `task` with a store built from `config`, the same way the server builds it.
+fn with_store<F, Fut, T>(config: &Config, task: F) -> Result<T, anyhow::Error>
+where
+    F: FnOnce(PostgresStore) -> Fut,
+    Fut: Future<Output = Result<T, anyhow::Error>> + 'static,
+{
+    require_postgres(config)?;
+    postgres::schema::check(Dsn::new(config), config.on_schema_drift)?;
+    let db = PostgresStore::from_config(config)?;
+    actix_rt::System::new("onething").block_on(task(db))
+}
+
+/// Datastore errors are made for HTTP responses, so keep both halves for the operator.
+fn to_anyhow(e: TfError) -> anyhow::Error {
+    e.internal.context(e.external.text)
+}
+
+fn require_postgres(config: &Config) -> Result<(), anyhow::Error> {
+    if config.storage != Storage::Postgres {
+        bail!("this command needs storage to be postgres");
+    }
+    Ok(())
+}
+
+fn migrate(config: &Config, command: MigrateCommand) -> Result<(), anyhow::Error> {
+    require_postgres(config)?;
+    let migrator = Migrator::connect(Dsn::new(config))?;
+    match command {
+        MigrateCommand::Up => {
+            migrator.up()?;
+        }
+        MigrateCommand::Down => {
+            if migrator.down()?.is_none() {
+                println!("No migrations are applied");
+            }
+        }
+        MigrateCommand::Redo => {
+            if migrator.redo()?.is_none() {
+                println!("No migrations are applied");
+            }
+        }
+        MigrateCommand::Status => {
+            println!("{:<24} Migration", "Applied At");
+            for status in migrator.status()? {
+                let applied_at = match (status.applied, status.applied_at) {
+                    (true, Some(at)) => at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    (true, None) => "applied".to_owned(),
+                    (false, _) => "pending".to_owned(),
+                };
+                let name = status.name.map_or_else(
+                    || format!("{} (unknown to this binary)", status.version),
+                    str::to_owned,
+                );
+                println!("{:<24} {}", applied_at, name);
+            }
+        }
+    }
+    Ok(())
+}
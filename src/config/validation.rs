@@ -0,0 +1,272 @@
+//! Checks that config values make sense, so that mistakes are reported up front, with where the
+//! bad value came from, rather than causing a panic or a confusing failure later.
+use crate::config::{Config, Storage};
+use serde::de::{self, Deserializer, Visitor};
+use std::fmt;
+use std::path::PathBuf;
+use tracing_subscriber::filter::EnvFilter;
+use url::Url;
+
+/// Where a config value was set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    /// A config file, and the line within it (if known)
+    File { path: PathBuf, line: Option<usize> },
+    /// An env var, e.g. QUIET_DB_POOL_SIZE
+    Env(String),
+    /// A CLI flag, e.g. --db_pool_size
+    Flag(String),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::File {
+                path,
+                line: Some(line),
+            } => write!(f, "{}:{}", path.display(), line),
+            Origin::File { path, line: None } => write!(f, "{}", path.display()),
+            Origin::Env(var) => write!(f, "env var {}", var),
+            Origin::Flag(key) => write!(f, "flag --{}", key),
+        }
+    }
+}
+
+/// Something wrong with the config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub origin: Option<Origin>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.origin {
+            Some(origin) => write!(f, "{}: {}", origin, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Every problem found in the config, rather than just the first one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problems(pub Vec<Problem>);
+
+impl fmt::Display for Problems {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, problem) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Problems {}
+
+/// Longest `db_connection_timeout` that makes sense. Any longer, and requests would time out first.
+const MAX_CONNECTION_TIMEOUT_SECS: u64 = 300;
+
+/// Longest `request_deadline` that makes sense. Clients and load balancers give up long before.
+const MAX_REQUEST_DEADLINE_SECS: u64 = 600;
+
+/// Longest `read_your_writes_window` that makes sense. Replicas lagging by more need fixing.
+const MAX_READ_YOUR_WRITES_WINDOW_SECS: u64 = 600;
+
+/// Longest `business_metrics_interval` that makes sense. Any longer, and the metrics aren't useful.
+const MAX_BUSINESS_METRICS_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+/// Longest `shutdown_timeout` that makes sense. Orchestrators usually kill the process long before.
+const MAX_SHUTDOWN_TIMEOUT_SECS: u64 = 600;
+
+impl Config {
+    /// Check values are sensible. Returns the key and description of each problem.
+    pub fn validate(&self) -> Vec<(&'static str, String)> {
+        let mut problems = Vec::new();
+        for (key, address) in &[
+            ("userfacing_listen_address", &self.userfacing_listen_address),
+            ("admin_listen_address", &self.admin_listen_address),
+            ("metrics_address", &self.metrics_address),
+        ] {
+            if let Err(e) = validate_address(address) {
+                problems.push((*key, e));
+            }
+        }
+        match (&self.metrics_bearer_token, &self.metrics_basic_auth) {
+            (Some(_), Some(_)) => problems.push((
+                "metrics_basic_auth",
+                "can't be set as well as metrics_bearer_token".to_owned(),
+            )),
+            (Some(token), None) if token.is_empty() => {
+                problems.push(("metrics_bearer_token", "must not be empty".to_owned()))
+            }
+            (None, Some(credentials)) if !credentials.contains(':') => problems.push((
+                "metrics_basic_auth",
+                "must be <username>:<password>".to_owned(),
+            )),
+            _ => {}
+        }
+        if !(1..=MAX_REQUEST_DEADLINE_SECS).contains(&self.request_deadline) {
+            problems.push((
+                "request_deadline",
+                format!(
+                    "must be between 1 and {} seconds",
+                    MAX_REQUEST_DEADLINE_SECS
+                ),
+            ));
+        }
+        if self.max_body_size == 0 {
+            problems.push(("max_body_size", "must be greater than 0".to_owned()));
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log_filter) {
+            problems.push(("log_filter", format!("invalid filter: {}", e)));
+        }
+        match self.storage {
+            Storage::Postgres => {
+                if self.db_dsn.trim().is_empty() {
+                    problems.push(("db_dsn", "must be set when storage is postgres".to_owned()));
+                }
+                for (key, set) in &[
+                    ("dev_fixtures_file", self.dev_fixtures_file.is_some()),
+                    ("dev_data_file", self.dev_data_file.is_some()),
+                ] {
+                    if *set {
+                        problems.push((*key, "can only be set when storage is memory".to_owned()));
+                    }
+                }
+            }
+            Storage::Memory => {
+                if self.auto_migrate {
+                    problems.push(("auto_migrate", "needs storage to be postgres".to_owned()));
+                }
+            }
+        }
+        if self.db_replica_dsns.iter().any(|dsn| dsn.trim().is_empty()) {
+            problems.push(("db_replica_dsns", "must not contain empty DSNs".to_owned()));
+        }
+        if self.read_your_writes_window > MAX_READ_YOUR_WRITES_WINDOW_SECS {
+            problems.push((
+                "read_your_writes_window",
+                format!(
+                    "must be at most {} seconds",
+                    MAX_READ_YOUR_WRITES_WINDOW_SECS
+                ),
+            ));
+        }
+        if self.db_pool_size == 0 {
+            problems.push(("db_pool_size", "must be greater than 0".to_owned()));
+        }
+        if !(1..=MAX_CONNECTION_TIMEOUT_SECS).contains(&self.db_connection_timeout) {
+            problems.push((
+                "db_connection_timeout",
+                format!(
+                    "must be between 1 and {} seconds",
+                    MAX_CONNECTION_TIMEOUT_SECS
+                ),
+            ));
+        }
+        if self.slow_query_threshold_ms == Some(0) {
+            problems.push((
+                "slow_query_threshold_ms",
+                "must be greater than 0".to_owned(),
+            ));
+        }
+        if self.slow_query_plan_file.is_some() && self.slow_query_threshold_ms.is_none() {
+            problems.push((
+                "slow_query_plan_file",
+                "needs slow_query_threshold_ms to be set".to_owned(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.slow_query_plan_sample_rate) {
+            problems.push((
+                "slow_query_plan_sample_rate",
+                "must be between 0 and 1".to_owned(),
+            ));
+        }
+        if !(1..=MAX_BUSINESS_METRICS_INTERVAL_SECS).contains(&self.business_metrics_interval) {
+            problems.push((
+                "business_metrics_interval",
+                format!(
+                    "must be between 1 and {} seconds",
+                    MAX_BUSINESS_METRICS_INTERVAL_SECS
+                ),
+            ));
+        }
+        if !(1..=MAX_SHUTDOWN_TIMEOUT_SECS).contains(&self.shutdown_timeout) {
+            problems.push((
+                "shutdown_timeout",
+                format!(
+                    "must be between 1 and {} seconds",
+                    MAX_SHUTDOWN_TIMEOUT_SECS
+                ),
+            ));
+        }
+        if let Some(endpoint) = &self.otlp_endpoint {
+            match Url::parse(endpoint) {
+                Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+                Ok(_) => problems.push(("otlp_endpoint", "must be an http(s) URL".to_owned())),
+                Err(e) => problems.push(("otlp_endpoint", format!("invalid URL: {}", e))),
+            }
+        }
+        problems
+    }
+}
+
+/// Addresses should be <address>:<port>. The address can be a hostname, so it isn't resolved here.
+fn validate_address(address: &str) -> Result<(), String> {
+    let invalid = || format!("`{}` should be <address>:<port>", address);
+    let colon = address.rfind(':').ok_or_else(invalid)?;
+    let (host, port) = (&address[..colon], &address[colon + 1..]);
+    if host.is_empty() || port.parse::<u16>().is_err() {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// The 1-based line number where `key` is set in a TOML file.
+pub fn line_of_key(contents: &str, key: &str) -> Option<usize> {
+    contents
+        .lines()
+        .position(|line| {
+            line.trim_start()
+                .strip_prefix(key)
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        })
+        .map(|i| i + 1)
+}
+
+/// Names of every field in `Config`, i.e. every key the config can contain. Serde knows these, so
+/// ask it rather than maintaining a list which could drift out of date.
+pub fn config_keys() -> &'static [&'static str] {
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de, 'a> Deserializer<'de> for FieldNames<'a> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("only structs have field names"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("only field names were needed"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = <Config as de::Deserialize>::deserialize(FieldNames(&mut fields));
+    fields
+}
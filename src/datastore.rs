@@ -1,4 +1,44 @@
+pub mod filter;
+pub mod memory;
 pub mod postfilters;
 pub mod postgres;
 pub mod structs;
+pub mod synthetic;
 pub mod tables;
+
+use crate::datastore::{
+    postfilters::PostFilters,
+    structs::{NewPost, NewUser, Post, User},
+};
+use crate::twoface::Fallible;
+use async_trait::async_trait;
+use uuid::Uuid;
+
+/// Stores posts, users and which users follow each other. The API is generic over this, so that
+/// handlers can be tested without a database.
+#[async_trait]
+pub trait Datastore: Send + Sync + 'static {
+    async fn new_post(&self, new_post: NewPost) -> Fallible<Post>;
+
+    /// Posts which match `filters`, oldest first.
+    async fn list_posts(&self, filters: PostFilters) -> Fallible<Vec<Post>>;
+
+    async fn find_post(&self, user_id: Uuid, id: Uuid) -> Fallible<Option<Post>>;
+
+    /// Mark a post as deleted. Returns the deleted post, or None if the user has no such post.
+    async fn delete_post(&self, user_id: Uuid, id: Uuid) -> Fallible<Option<Post>>;
+
+    #[allow(dead_code)] // not served by the API yet
+    /// Posts by the users which `user_id` follows, oldest first.
+    async fn timeline(&self, user_id: Uuid, num_posts: u8) -> Fallible<Vec<Post>>;
+
+    #[allow(dead_code)] // not served by the API yet
+    async fn new_user(&self, new_user: NewUser) -> Fallible<User>;
+
+    #[allow(dead_code)] // not served by the API yet
+    async fn get_user(&self, user_id: Uuid) -> Fallible<Option<User>>;
+
+    #[allow(dead_code)] // not served by the API yet
+    /// Make `reader` follow the posts of `poster`. Following someone twice has no effect.
+    async fn follow(&self, reader: Uuid, poster: Uuid) -> Fallible<()>;
+}
@@ -0,0 +1,397 @@
+//! One definition of what it means for a post to match a filter, compiled both to a SQL `WHERE`
+//! clause (for Postgres) and to a Rust predicate (for `MemoryStore`).
+//!
+//! Filters are built from a handful of primitive conditions, combined with AND, OR and NOT. Only
+//! the primitives are implemented twice, and each is small enough to check by eye. Every primitive
+//! is total: it's never NULL in SQL, so boolean logic (in particular NOT) works the same way in
+//! both languages.
+use crate::datastore::{structs::Post, tables::posts};
+use chrono::{offset::Utc, DateTime, SubsecRound};
+use diesel::{
+    dsl::{not, sql},
+    expression::BoxableExpression,
+    pg::Pg,
+    sql_types::{Bool, Text},
+    BoolExpressionMethods, ExpressionMethods, IntoSql, TextExpressionMethods,
+};
+use uuid::Uuid;
+
+/// A SQL condition on the posts table.
+pub type SqlFilter = Box<dyn BoxableExpression<posts::table, Pg, SqlType = Bool>>;
+
+/// A filter on posts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    /// Every filter matches. An empty `And` matches every post.
+    And(Vec<Filter>),
+    /// Any filter matches. An empty `Or` matches no posts.
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Condition(Condition),
+}
+
+/// A condition on a single column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Equals(UuidColumn, Uuid),
+    /// The column is one of these. An empty list matches nothing.
+    In(UuidColumn, Vec<Uuid>),
+    /// The column is set, and is strictly before the given time.
+    Before(TimeColumn, DateTime<Utc>),
+    /// The column is set, and is strictly after the given time.
+    After(TimeColumn, DateTime<Utc>),
+    IsSet(TimeColumn),
+    /// The text contains this substring, case-sensitively. Unlike a raw SQL `LIKE` pattern, `%`
+    /// and `_` are matched literally.
+    TextContains(String),
+    /// The text contains `#tag` as a word, i.e. with a space (or the start or end of the text) on
+    /// either side.
+    HasTag(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UuidColumn {
+    Id,
+    UserId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeColumn {
+    CreatedAt,
+    DeletedAt,
+}
+
+impl Filter {
+    pub fn id(id: Uuid) -> Self {
+        Filter::Condition(Condition::Equals(UuidColumn::Id, id))
+    }
+
+    pub fn user_id(user_id: Uuid) -> Self {
+        Filter::Condition(Condition::Equals(UuidColumn::UserId, user_id))
+    }
+
+    pub fn id_in(ids: &[Uuid]) -> Self {
+        Filter::Condition(Condition::In(UuidColumn::Id, ids.to_vec()))
+    }
+
+    pub fn user_id_in(user_ids: &[Uuid]) -> Self {
+        Filter::Condition(Condition::In(UuidColumn::UserId, user_ids.to_vec()))
+    }
+
+    pub fn is_deleted(is_deleted: bool) -> Self {
+        let deleted = Filter::Condition(Condition::IsSet(TimeColumn::DeletedAt));
+        if is_deleted {
+            deleted
+        } else {
+            deleted.negate()
+        }
+    }
+
+    pub fn text_contains(substring: &str) -> Self {
+        Filter::Condition(Condition::TextContains(substring.to_owned()))
+    }
+
+    pub fn has_tag(tag: &str) -> Self {
+        Filter::Condition(Condition::HasTag(tag.to_owned()))
+    }
+
+    pub fn before(column: TimeColumn, time: DateTime<Utc>) -> Self {
+        Filter::Condition(Condition::Before(column, db_precision(time)))
+    }
+
+    pub fn after(column: TimeColumn, time: DateTime<Utc>) -> Self {
+        Filter::Condition(Condition::After(column, db_precision(time)))
+    }
+
+    /// The post had been created, and not yet deleted, at `time`.
+    pub fn existed_at(time: DateTime<Utc>) -> Self {
+        Filter::And(vec![
+            Filter::before(TimeColumn::CreatedAt, time),
+            Filter::Or(vec![
+                Filter::is_deleted(false),
+                Filter::after(TimeColumn::DeletedAt, time),
+            ]),
+        ])
+    }
+
+    pub fn negate(self) -> Self {
+        Filter::Not(Box::new(self))
+    }
+
+    /// Does the post match this filter?
+    pub fn matches(&self, post: &Post) -> bool {
+        match self {
+            Filter::And(filters) => filters.iter().all(|f| f.matches(post)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(post)),
+            Filter::Not(filter) => !filter.matches(post),
+            Filter::Condition(condition) => condition.matches(post),
+        }
+    }
+
+    /// A SQL condition which matches the same posts as `matches`.
+    pub fn to_sql(&self) -> SqlFilter {
+        match self {
+            Filter::And(filters) => filters
+                .iter()
+                .map(Filter::to_sql)
+                .fold(Box::new(sql::<Bool>("TRUE")), |a, b| Box::new(a.and(b))),
+            Filter::Or(filters) => filters
+                .iter()
+                .map(Filter::to_sql)
+                .fold(Box::new(sql::<Bool>("FALSE")), |a, b| Box::new(a.or(b))),
+            Filter::Not(filter) => Box::new(not(filter.to_sql())),
+            Filter::Condition(condition) => condition.to_sql(),
+        }
+    }
+}
+
+impl Condition {
+    fn matches(&self, post: &Post) -> bool {
+        match self {
+            Condition::Equals(UuidColumn::Id, id) => post.id == *id,
+            Condition::Equals(UuidColumn::UserId, id) => post.user_id == *id,
+            Condition::In(UuidColumn::Id, ids) => ids.contains(&post.id),
+            Condition::In(UuidColumn::UserId, ids) => ids.contains(&post.user_id),
+            Condition::Before(column, time) => column.get(post).is_some_and(|t| t < *time),
+            Condition::After(column, time) => column.get(post).is_some_and(|t| t > *time),
+            Condition::IsSet(column) => column.get(post).is_some(),
+            Condition::TextContains(substring) => post.text.contains(substring.as_str()),
+            Condition::HasTag(tag) => format!(" {} ", post.text).contains(&format!(" #{} ", tag)),
+        }
+    }
+
+    fn to_sql(&self) -> SqlFilter {
+        match self {
+            Condition::Equals(UuidColumn::Id, id) => Box::new(posts::id.eq(*id)),
+            Condition::Equals(UuidColumn::UserId, id) => Box::new(posts::user_id.eq(*id)),
+            Condition::In(UuidColumn::Id, ids) => Box::new(posts::id.eq_any(ids.clone())),
+            Condition::In(UuidColumn::UserId, ids) => Box::new(posts::user_id.eq_any(ids.clone())),
+            Condition::Before(TimeColumn::CreatedAt, time) => Box::new(posts::created_at.lt(*time)),
+            Condition::After(TimeColumn::CreatedAt, time) => Box::new(posts::created_at.gt(*time)),
+            // deleted_at is nullable, so check it's set, otherwise these would be NULL
+            Condition::Before(TimeColumn::DeletedAt, time) => Box::new(
+                posts::deleted_at
+                    .is_not_null()
+                    .and(posts::deleted_at.lt(*time)),
+            ),
+            Condition::After(TimeColumn::DeletedAt, time) => Box::new(
+                posts::deleted_at
+                    .is_not_null()
+                    .and(posts::deleted_at.gt(*time)),
+            ),
+            Condition::IsSet(TimeColumn::CreatedAt) => Box::new(sql::<Bool>("TRUE")),
+            Condition::IsSet(TimeColumn::DeletedAt) => Box::new(posts::deleted_at.is_not_null()),
+            Condition::TextContains(substring) => {
+                Box::new(posts::text.like(format!("%{}%", escape_like(substring))))
+            }
+            Condition::HasTag(tag) => Box::new(
+                " ".into_sql::<Text>()
+                    .concat(posts::text)
+                    .concat(" ")
+                    .like(format!("% #{} %", escape_like(tag))),
+            ),
+        }
+    }
+}
+
+impl TimeColumn {
+    fn get(self, post: &Post) -> Option<DateTime<Utc>> {
+        match self {
+            TimeColumn::CreatedAt => Some(post.created_at),
+            TimeColumn::DeletedAt => post.deleted_at,
+        }
+        .map(db_precision)
+    }
+}
+
+/// Postgres stores timestamps to the microsecond, so compare them at that precision.
+pub fn db_precision(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.trunc_subsecs(6)
+}
+
+/// Escape `LIKE` wildcards (and the escape character itself), so that they match literally.
+fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '%' || c == '_' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::{
+        structs::{Content, NewUser},
+        tables::users,
+    };
+    use chrono::{Duration, TimeZone};
+    use diesel::{debug_query, Connection, PgConnection, QueryDsl, RunQueryDsl};
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    #[test]
+    fn test_to_sql() {
+        let filter = Filter::And(vec![
+            Filter::text_contains("50%_off"),
+            Filter::is_deleted(false),
+        ]);
+        let query = posts::table.select(posts::id).filter(filter.to_sql());
+        let sql = debug_query::<Pg, _>(&query).to_string();
+        assert!(sql.contains(
+            r#"TRUE AND "posts"."text" LIKE $1 AND NOT ("posts"."deleted_at" IS NOT NULL)"#
+        ));
+        assert!(sql.ends_with(r#"binds: ["%50\\%\\_off%"]"#), "{}", sql);
+    }
+
+    const TEXT_FRAGMENTS: &[&str] = &[
+        "release",
+        "Release",
+        "50%",
+        "a_b",
+        "back\\slash",
+        "ops",
+        " ",
+        "#ops",
+        "#a_b",
+    ];
+
+    /// Posts whose timestamps are spread over a few seconds, so that filters on time often fall
+    /// between them or exactly on them.
+    fn random_posts(rng: &mut StdRng, user_ids: &[Uuid], n: usize) -> Vec<Post> {
+        let start = Utc.timestamp(1_600_000_000, 0);
+        let random_time =
+            |rng: &mut StdRng| start + Duration::microseconds(rng.gen_range(0, 5_000));
+        (0..n)
+            .map(|_| {
+                let created_at = random_time(rng);
+                let deleted_at = if rng.gen_bool(0.4) {
+                    Some(created_at + Duration::microseconds(rng.gen_range(0, 2_000)))
+                } else {
+                    None
+                };
+                let words = rng.gen_range(0, 4);
+                let text = (0..words)
+                    .map(|_| *TEXT_FRAGMENTS.choose(rng).unwrap())
+                    .collect();
+                Post {
+                    id: Uuid::new_v4(),
+                    created_at,
+                    deleted_at,
+                    content: Content::None,
+                    text,
+                    user_id: *user_ids.choose(rng).unwrap(),
+                }
+            })
+            .collect()
+    }
+
+    fn random_filter(rng: &mut StdRng, posts: &[Post], depth: u32) -> Filter {
+        let post = posts.choose(rng).unwrap();
+        // Sometimes exactly a post's timestamp, sometimes just either side of it
+        let time = post.created_at + Duration::microseconds(rng.gen_range(-1, 2));
+        let column = *[TimeColumn::CreatedAt, TimeColumn::DeletedAt]
+            .choose(rng)
+            .unwrap();
+        let choice = if depth == 0 {
+            rng.gen_range(3, 14)
+        } else {
+            rng.gen_range(0, 14)
+        };
+        let mut children = || {
+            (0..rng.gen_range(0, 4))
+                .map(|_| random_filter(rng, posts, depth - 1))
+                .collect()
+        };
+        match choice {
+            0 => Filter::And(children()),
+            1 => Filter::Or(children()),
+            2 => random_filter(rng, posts, depth - 1).negate(),
+            3 => Filter::id(post.id),
+            4 => Filter::user_id(post.user_id),
+            5 => Filter::is_deleted(rng.gen()),
+            6 => Filter::text_contains(TEXT_FRAGMENTS.choose(rng).unwrap()),
+            7 => Filter::existed_at(time),
+            8 => Filter::Condition(Condition::Before(column, time)),
+            9 => Filter::Condition(Condition::After(column, time)),
+            10 => Filter::has_tag(&TEXT_FRAGMENTS.choose(rng).unwrap().replace('#', "")),
+            11 => {
+                let n = rng.gen_range(0, 4);
+                let mut ids: Vec<_> = posts.choose_multiple(rng, n).map(|p| p.id).collect();
+                ids.push(Uuid::new_v4());
+                Filter::id_in(&ids)
+            }
+            12 => {
+                let n = rng.gen_range(0, 3);
+                let user_ids: Vec<_> = posts.choose_multiple(rng, n).map(|p| p.user_id).collect();
+                Filter::user_id_in(&user_ids)
+            }
+            _ => Filter::Condition(Condition::IsSet(column)),
+        }
+    }
+
+    /// Checks that SQL and Rust agree on which posts match, for many random filters and posts.
+    /// Needs a migrated Postgres database, e.g.
+    /// `QUIET_TEST_DB_DSN=postgres://localhost/quiet cargo test -- --ignored`. Nothing is
+    /// committed to the database.
+    #[test]
+    #[ignore]
+    fn test_sql_and_rust_agree() {
+        let dsn = std::env::var("QUIET_TEST_DB_DSN").expect("QUIET_TEST_DB_DSN must be set");
+        let conn = PgConnection::establish(&dsn).unwrap();
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            conn.test_transaction::<_, diesel::result::Error, _>(|| {
+                let names = ["a", "b", "c"].iter().map(|name| NewUser {
+                    name: name.to_string(),
+                });
+                let user_ids: Vec<Uuid> = diesel::insert_into(users::table)
+                    .values(&names.collect::<Vec<_>>())
+                    .returning(users::id)
+                    .get_results(&conn)?;
+                let posts = random_posts(&mut rng, &user_ids, 200);
+                let rows: Vec<_> = posts
+                    .iter()
+                    .map(|p| {
+                        (
+                            posts::id.eq(p.id),
+                            posts::created_at.eq(p.created_at),
+                            posts::deleted_at.eq(p.deleted_at),
+                            posts::content.eq(p.content),
+                            posts::text.eq(&p.text),
+                            posts::user_id.eq(p.user_id),
+                        )
+                    })
+                    .collect();
+                diesel::insert_into(posts::table)
+                    .values(&rows)
+                    .execute(&conn)?;
+                let ids: Vec<_> = posts.iter().map(|p| p.id).collect();
+
+                for _ in 0..100 {
+                    let filter = random_filter(&mut rng, &posts, 3);
+                    let mut from_sql: Vec<Uuid> = posts::table
+                        .select(posts::id)
+                        .filter(posts::id.eq_any(&ids))
+                        .filter(filter.to_sql())
+                        .load(&conn)?;
+                    let mut from_rust: Vec<Uuid> = posts
+                        .iter()
+                        .filter(|p| filter.matches(p))
+                        .map(|p| p.id)
+                        .collect();
+                    from_sql.sort();
+                    from_rust.sort();
+                    assert_eq!(
+                        from_sql, from_rust,
+                        "seed {}: SQL and Rust disagree on {:?}",
+                        seed, filter
+                    );
+                }
+                Ok(())
+            });
+        }
+    }
+}
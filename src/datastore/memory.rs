@@ -0,0 +1,465 @@
+//! A datastore which keeps everything in memory, for local development and for tests which
+//! shouldn't need Postgres. It can be seeded from a fixtures file, and saved to a data file so
+//! that its data survives restarts.
+use crate::datastore::{
+    filter::db_precision,
+    postfilters::PostFilters,
+    structs::{Follow, NewPost, NewUser, Post, User},
+    Datastore,
+};
+use crate::twoface::Fallible;
+use actix_web::web;
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use chrono::offset::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// How often a `MemoryStore`'s writes are saved to its data file.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An implementation of `Datastore` backed by in-memory collections. Like the Postgres schema,
+/// posts and follows must refer to users which exist, and timestamps are only precise to the
+/// microsecond.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+    /// What `reset` restores.
+    fixtures: Snapshot,
+    /// Where the data is saved, if anywhere.
+    data_file: Option<PathBuf>,
+    /// Whether there are writes which haven't been saved yet.
+    dirty: AtomicBool,
+    /// Held while saving, so that an older snapshot can't overwrite a newer one.
+    saving: Mutex<()>,
+}
+
+#[derive(Default)]
+struct State {
+    users: HashMap<Uuid, User>,
+    posts: Vec<Post>,
+    follows: HashSet<Follow>,
+}
+
+/// Everything in a `MemoryStore`. Fixtures files and data files are snapshots, as JSON.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    #[serde(default)]
+    pub users: Vec<User>,
+    #[serde(default)]
+    pub posts: Vec<Post>,
+    #[serde(default)]
+    pub follows: Vec<Follow>,
+}
+
+/// How much a `MemoryStore` holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Counts {
+    pub users: usize,
+    pub posts: usize,
+    pub follows: usize,
+}
+
+impl MemoryStore {
+    /// A store seeded from `fixtures_file` (or empty, if there isn't one). If `data_file` is
+    /// given, the store is saved there by `flush`, and later stores start from it instead of the
+    /// fixtures.
+    pub fn open(
+        fixtures_file: Option<&Path>,
+        data_file: Option<PathBuf>,
+    ) -> Result<Self, anyhow::Error> {
+        let fixtures = match fixtures_file {
+            Some(path) => Snapshot::read(path)?,
+            None => Snapshot::default(),
+        };
+        let state = match &data_file {
+            Some(path) if path.exists() => Snapshot::read(path)?.into_state()?,
+            _ => fixtures.clone().into_state()?,
+        };
+        let counts = state.counts();
+        let store = Self {
+            state: Mutex::new(state),
+            fixtures,
+            data_file,
+            dirty: AtomicBool::new(true),
+            saving: Mutex::default(),
+        };
+        store.flush();
+        info!(
+            users = counts.users,
+            posts = counts.posts,
+            follows = counts.follows,
+            "opened in-memory datastore"
+        );
+        Ok(store)
+    }
+
+    /// Replace everything with the fixtures.
+    pub fn reset(&self) -> Counts {
+        let mut state = self.state();
+        *state = self
+            .fixtures
+            .clone()
+            .into_state()
+            .expect("fixtures were checked when the store was opened");
+        let counts = state.counts();
+        self.mark_dirty();
+        info!(
+            users = counts.users,
+            posts = counts.posts,
+            follows = counts.follows,
+            "reset in-memory datastore to its fixtures"
+        );
+        counts
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("memory store lock poisoned")
+    }
+
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// Save unsaved writes to the data file, if there is one. This blocks on file I/O, so it
+    /// shouldn't run on the server's threads. If the writes can't be saved, they're retried by
+    /// the next flush.
+    pub fn flush(&self) {
+        let path = match &self.data_file {
+            Some(path) => path,
+            None => return,
+        };
+        let _saving = self.saving.lock().expect("memory store save lock poisoned");
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        // The state is only locked while it's copied, not while it's written
+        let snapshot = self.state().to_snapshot();
+        if let Err(e) = snapshot.write(path) {
+            self.mark_dirty();
+            warn!(path = %path.display(), error = %format!("{:#}", e), "couldn't save data");
+        }
+    }
+}
+
+/// Flush `store` every `FLUSH_INTERVAL`, on Actix's blocking threads. Runs forever, so it should
+/// be spawned onto the Actix runtime.
+pub async fn flush_periodically(store: Arc<MemoryStore>) {
+    loop {
+        actix_rt::time::delay_for(FLUSH_INTERVAL).await;
+        let store = Arc::clone(&store);
+        let flushed = web::block(move || {
+            store.flush();
+            Ok::<_, ()>(())
+        });
+        if let Err(e) = flushed.await {
+            warn!(error = %e, "couldn't flush in-memory datastore");
+        }
+    }
+}
+
+impl State {
+    fn require_user(&self, user_id: Uuid) -> Fallible<()> {
+        if self.users.contains_key(&user_id) {
+            Ok(())
+        } else {
+            Err(anyhow!("no user with ID {}", user_id).into())
+        }
+    }
+
+    fn counts(&self) -> Counts {
+        Counts {
+            users: self.users.len(),
+            posts: self.posts.len(),
+            follows: self.follows.len(),
+        }
+    }
+
+    /// Sorted, so that data files only change where the data did.
+    fn to_snapshot(&self) -> Snapshot {
+        let mut users: Vec<_> = self.users.values().cloned().collect();
+        users.sort_by_key(|user| (user.created_at, user.id));
+        let mut follows: Vec<_> = self.follows.iter().copied().collect();
+        follows.sort_by_key(|follow| (follow.reads, follow.posts));
+        Snapshot {
+            users,
+            posts: self.posts.clone(),
+            follows,
+        }
+    }
+}
+
+impl Snapshot {
+    fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("couldn't read {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("couldn't parse {}", path.display()))
+    }
+
+    /// Write to a temporary file, then move it into place, so that a crash mid-write can't leave
+    /// a truncated file.
+    pub fn write(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// Check the snapshot the way the Postgres schema would, and round timestamps to its
+    /// precision.
+    fn into_state(self) -> Result<State, anyhow::Error> {
+        let mut state = State::default();
+        for mut user in self.users {
+            user.created_at = db_precision(user.created_at);
+            user.deleted_at = user.deleted_at.map(db_precision);
+            if let Some(user) = state.users.insert(user.id, user) {
+                bail!("there are two users with ID {}", user.id);
+            }
+        }
+        let mut post_ids = HashSet::new();
+        for mut post in self.posts {
+            if !post_ids.insert(post.id) {
+                bail!("there are two posts with ID {}", post.id);
+            }
+            if !state.users.contains_key(&post.user_id) {
+                bail!(
+                    "post {} is by user {}, who doesn't exist",
+                    post.id,
+                    post.user_id
+                );
+            }
+            post.created_at = db_precision(post.created_at);
+            post.deleted_at = post.deleted_at.map(db_precision);
+            state.posts.push(post);
+        }
+        for follow in self.follows {
+            for user_id in &[follow.posts, follow.reads] {
+                if !state.users.contains_key(user_id) {
+                    bail!("a follow refers to user {}, who doesn't exist", user_id);
+                }
+            }
+            state.follows.insert(follow);
+        }
+        Ok(state)
+    }
+}
+
+/// Oldest posts first, at most `limit` of them.
+fn oldest_first<'a>(posts: impl Iterator<Item = &'a Post>, limit: usize) -> Vec<Post> {
+    let mut posts: Vec<_> = posts.cloned().collect();
+    posts.sort_by_key(|post| post.created_at);
+    posts.truncate(limit);
+    posts
+}
+
+#[async_trait]
+impl Datastore for MemoryStore {
+    async fn new_post(&self, new_post: NewPost) -> Fallible<Post> {
+        let mut state = self.state();
+        state.require_user(new_post.user_id)?;
+        let post = Post {
+            id: Uuid::new_v4(),
+            created_at: db_precision(Utc::now()),
+            deleted_at: None,
+            content: new_post.content,
+            text: new_post.text,
+            user_id: new_post.user_id,
+        };
+        state.posts.push(post.clone());
+        self.mark_dirty();
+        Ok(post)
+    }
+
+    async fn list_posts(&self, filters: PostFilters) -> Fallible<Vec<Post>> {
+        let state = self.state();
+        let matching = state.posts.iter().filter(|post| post.matches(&filters));
+        Ok(oldest_first(matching, filters.limit as usize))
+    }
+
+    async fn find_post(&self, user_id: Uuid, id: Uuid) -> Fallible<Option<Post>> {
+        let state = self.state();
+        let post = state
+            .posts
+            .iter()
+            .find(|post| post.id == id && post.user_id == user_id);
+        Ok(post.cloned())
+    }
+
+    async fn delete_post(&self, user_id: Uuid, id: Uuid) -> Fallible<Option<Post>> {
+        let mut state = self.state();
+        let post = state
+            .posts
+            .iter_mut()
+            .find(|post| post.id == id && post.user_id == user_id);
+        let deleted = post.map(|post| {
+            post.deleted_at = Some(db_precision(Utc::now()));
+            post.clone()
+        });
+        if deleted.is_some() {
+            self.mark_dirty();
+        }
+        Ok(deleted)
+    }
+
+    async fn timeline(&self, user_id: Uuid, num_posts: u8) -> Fallible<Vec<Post>> {
+        let state = self.state();
+        let followed: HashSet<_> = state
+            .follows
+            .iter()
+            .filter(|follow| follow.reads == user_id)
+            .map(|follow| follow.posts)
+            .collect();
+        let posts = state
+            .posts
+            .iter()
+            .filter(|post| followed.contains(&post.user_id));
+        Ok(oldest_first(posts, num_posts as usize))
+    }
+
+    async fn new_user(&self, new_user: NewUser) -> Fallible<User> {
+        let user = User {
+            id: Uuid::new_v4(),
+            created_at: db_precision(Utc::now()),
+            deleted_at: None,
+            name: new_user.name,
+        };
+        let mut state = self.state();
+        state.users.insert(user.id, user.clone());
+        self.mark_dirty();
+        Ok(user)
+    }
+
+    async fn get_user(&self, user_id: Uuid) -> Fallible<Option<User>> {
+        Ok(self.state().users.get(&user_id).cloned())
+    }
+
+    async fn follow(&self, reader: Uuid, poster: Uuid) -> Fallible<()> {
+        let mut state = self.state();
+        state.require_user(reader)?;
+        state.require_user(poster)?;
+        state.follows.insert(Follow {
+            posts: poster,
+            reads: reader,
+        });
+        self.mark_dirty();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::structs::Content;
+    use chrono::{DateTime, Duration};
+
+    #[actix_rt::test]
+    async fn test_timeline() {
+        let store = MemoryStore::default();
+        let new_user = |name: &str| NewUser {
+            name: name.to_owned(),
+        };
+        let reader = store.new_user(new_user("reader")).await.unwrap();
+        let followed = store.new_user(new_user("followed")).await.unwrap();
+        let stranger = store.new_user(new_user("stranger")).await.unwrap();
+        store.follow(reader.id, followed.id).await.unwrap();
+        store.follow(reader.id, followed.id).await.unwrap();
+        assert!(store.follow(reader.id, Uuid::new_v4()).await.is_err());
+
+        let mut posted = Vec::new();
+        for user in &[&followed, &stranger, &followed] {
+            let post = NewPost {
+                content: Content::None,
+                text: format!("by {}", user.name),
+                user_id: user.id,
+            };
+            posted.push(store.new_post(post).await.unwrap());
+        }
+        let timeline = store.timeline(reader.id, 10).await.unwrap();
+        assert_eq!(timeline, vec![posted[0].clone(), posted[2].clone()]);
+        assert_eq!(store.timeline(reader.id, 1).await.unwrap().len(), 1);
+        assert!(store.timeline(stranger.id, 10).await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_data_file_survives_restarts_until_reset() {
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/deployment/dev_fixtures.json");
+        let fixtures = Some(Path::new(fixtures));
+        let data_file = std::env::temp_dir().join(format!("{}-data.json", Uuid::new_v4()));
+        let store = MemoryStore::open(fixtures, Some(data_file.clone())).unwrap();
+        let seeded = store.state().counts();
+        assert!(seeded.users > 0 && seeded.posts > 0 && seeded.follows > 0);
+
+        let user = store
+            .new_user(NewUser {
+                name: "new".to_owned(),
+            })
+            .await
+            .unwrap();
+        // Writes are only saved when the store is flushed
+        let unflushed = MemoryStore::open(fixtures, Some(data_file.clone())).unwrap();
+        assert_eq!(unflushed.get_user(user.id).await.unwrap(), None);
+        store.flush();
+        let store = MemoryStore::open(fixtures, Some(data_file.clone())).unwrap();
+        assert_eq!(store.get_user(user.id).await.unwrap(), Some(user.clone()));
+
+        assert_eq!(store.reset(), seeded);
+        assert_eq!(store.get_user(user.id).await.unwrap(), None);
+        store.flush();
+        let store = MemoryStore::open(fixtures, Some(data_file.clone())).unwrap();
+        assert_eq!(store.state().counts(), seeded);
+        std::fs::remove_file(data_file).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_snapshots_are_checked() {
+        let user = User {
+            id: Uuid::new_v4(),
+            created_at: "2020-10-01T09:00:00.123456789Z".parse().unwrap(),
+            deleted_at: None,
+            name: "user".to_owned(),
+        };
+        let post = Post {
+            id: Uuid::new_v4(),
+            created_at: user.created_at + Duration::hours(1),
+            deleted_at: None,
+            content: Content::None,
+            text: String::new(),
+            user_id: user.id,
+        };
+        let snapshot = Snapshot {
+            users: vec![user.clone()],
+            posts: vec![post.clone()],
+            follows: vec![],
+        };
+        let state = snapshot.clone().into_state().unwrap();
+        let created_at: DateTime<Utc> = "2020-10-01T09:00:00.123456Z".parse().unwrap();
+        assert_eq!(state.users[&user.id].created_at, created_at);
+
+        let orphaned_post = Snapshot {
+            users: vec![],
+            ..snapshot.clone()
+        };
+        assert!(orphaned_post.into_state().is_err());
+        let duplicate_post = Snapshot {
+            posts: vec![post.clone(), post],
+            ..snapshot.clone()
+        };
+        assert!(duplicate_post.into_state().is_err());
+        let orphaned_follow = Snapshot {
+            follows: vec![Follow {
+                posts: user.id,
+                reads: Uuid::new_v4(),
+            }],
+            ..snapshot
+        };
+        assert!(orphaned_follow.into_state().is_err());
+    }
+}
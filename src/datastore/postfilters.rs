@@ -1,18 +1,39 @@
 //! Ways to filter posts based on their fields. Filter semantics work just like SQL:
 //! If a field is unset, its filter won't be applied.
 //! If set, filter out posts that don't match the filter.
+use crate::datastore::filter::{Filter, TimeColumn};
+use crate::twoface::{Cause, Describe, ExternalError, Fallible};
+use anyhow::anyhow;
 use chrono::{offset::Utc, DateTime};
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer};
 use uuid::Uuid;
 
+/// Most IDs which can be listed in one filter. Each is a bind parameter, so a long list makes for
+/// a slow query.
+pub const MAX_LISTED_IDS: usize = 100;
+
 /// Filters that can be applied to queries on the datastore.
 #[derive(Default, Deserialize, Debug, Eq, PartialEq)]
 pub struct PostFilters {
     pub is_deleted: Option<bool>,
     pub text_contains: Option<String>,
     pub existed_at: Option<DateTime<Utc>>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    /// Only deleted posts can match `deleted_after` or `deleted_before`.
+    pub deleted_after: Option<DateTime<Utc>>,
+    pub deleted_before: Option<DateTime<Utc>>,
     pub id: Option<Uuid>,
+    /// Comma-separated, in a query string
+    #[serde(default, deserialize_with = "comma_separated")]
+    pub ids: Option<Vec<Uuid>>,
     pub user_id: Option<Uuid>,
+    /// Comma-separated, in a query string
+    #[serde(default, deserialize_with = "comma_separated")]
+    pub user_ids: Option<Vec<Uuid>>,
+    /// A filter from the query language, which posts must match as well as the fields above
+    #[serde(skip)]
+    pub query: Option<Filter>,
     /// Maximum number of posts to let match the filter
     #[serde(default = "default_limit")]
     pub limit: u8,
@@ -21,3 +42,124 @@ pub struct PostFilters {
 fn default_limit() -> u8 {
     100
 }
+
+/// Deserialize a comma-separated list, e.g. `ids=<uuid>,<uuid>`, since query strings have no lists.
+pub fn comma_separated<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<Uuid>>, D::Error> {
+    let list = match Option::<String>::deserialize(d)? {
+        Some(list) => list,
+        None => return Ok(None),
+    };
+    list.split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| Uuid::parse_str(id).map_err(de::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+impl PostFilters {
+    /// Check lists of IDs aren't empty or too long.
+    pub fn check_lists(&self) -> Fallible<()> {
+        for (key, list) in &[("ids", &self.ids), ("user_ids", &self.user_ids)] {
+            let len = list.as_ref().map(Vec::len);
+            let text = match len {
+                Some(0) => format!("{} must list at least one ID", key),
+                Some(len) if len > MAX_LISTED_IDS => {
+                    format!("{} can list at most {} IDs", key, MAX_LISTED_IDS)
+                }
+                _ => continue,
+            };
+            return Err(
+                anyhow!("{} listed {:?} IDs", key, len).describe(ExternalError {
+                    cause: Cause::UserInvalidField,
+                    text: text.into(),
+                }),
+            );
+        }
+        Ok(())
+    }
+
+    /// A filter matching posts which match every specified field.
+    pub fn to_filter(&self) -> Filter {
+        let mut filters = Vec::new();
+        if let Some(id) = self.id {
+            filters.push(Filter::id(id));
+        }
+        if let Some(ids) = &self.ids {
+            filters.push(Filter::id_in(ids));
+        }
+        if let Some(user_id) = self.user_id {
+            filters.push(Filter::user_id(user_id));
+        }
+        if let Some(user_ids) = &self.user_ids {
+            filters.push(Filter::user_id_in(user_ids));
+        }
+        if let Some(is_deleted) = self.is_deleted {
+            filters.push(Filter::is_deleted(is_deleted));
+        }
+        if let Some(substring) = &self.text_contains {
+            filters.push(Filter::text_contains(substring));
+        }
+        if let Some(existed_at) = self.existed_at {
+            filters.push(Filter::existed_at(existed_at));
+        }
+        let ranges = [
+            (
+                TimeColumn::CreatedAt,
+                self.created_after,
+                self.created_before,
+            ),
+            (
+                TimeColumn::DeletedAt,
+                self.deleted_after,
+                self.deleted_before,
+            ),
+        ];
+        for (column, after, before) in &ranges {
+            if let Some(after) = after {
+                filters.push(Filter::after(*column, *after));
+            }
+            if let Some(before) = before {
+                filters.push(Filter::before(*column, *before));
+            }
+        }
+        if let Some(query) = &self.query {
+            filters.push(query.clone());
+        }
+        Filter::And(filters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::web;
+
+    #[test]
+    fn test_lists_in_query_string() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let query = format!("ids={},%20{}&created_after=2026-01-01T00:00:00Z", a, b);
+        let filters = web::Query::<PostFilters>::from_query(&query)
+            .unwrap()
+            .into_inner();
+        assert_eq!(filters.ids, Some(vec![a, b]));
+        assert_eq!(filters.user_ids, None);
+        assert!(filters.check_lists().is_ok());
+
+        assert!(web::Query::<PostFilters>::from_query("ids=not-a-uuid").is_err());
+        let empty = web::Query::<PostFilters>::from_query("user_ids=").unwrap();
+        assert_eq!(
+            empty.check_lists().unwrap_err().to_string(),
+            "UserInvalidField: user_ids must list at least one ID"
+        );
+        let ids: Vec<_> = (0..=MAX_LISTED_IDS)
+            .map(|_| Uuid::new_v4().to_string())
+            .collect();
+        let query = format!("ids={}", ids.join(","));
+        let long = web::Query::<PostFilters>::from_query(&query).unwrap();
+        assert_eq!(
+            long.check_lists().unwrap_err().to_string(),
+            "UserInvalidField: ids can list at most 100 IDs"
+        );
+    }
+}
@@ -1,16 +1,31 @@
+mod breaker;
 mod errors;
+pub mod executor;
+pub mod migrations;
 pub mod postgres_client;
+mod replicas;
+pub mod schema;
+mod slow_queries;
 use crate::config::Config;
+use anyhow::{anyhow, Context};
+use breaker::Breaker;
 use diesel::{
     pg::PgConnection,
     r2d2::{ConnectionManager, Pool},
+    RunQueryDsl,
 };
+use executor::{Executor, ExecutorError};
 use prometheus::{
     core::{Collector, Desc},
     proto::MetricFamily,
-    IntGauge, Opts,
+    IntGaugeVec, Opts,
 };
-use std::time::Duration;
+pub use replicas::{must_read_primary, reading_own_writes};
+use replicas::{Replica, Replicas};
+use serde::Serialize;
+pub use slow_queries::SlowQueryLog;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 pub struct Dsn {
     secret: String,
@@ -22,6 +37,17 @@ impl Dsn {
             secret: config.db_dsn.clone(),
         }
     }
+
+    /// DSNs of each read replica.
+    pub fn replicas(config: &Config) -> Vec<Self> {
+        config
+            .db_replica_dsns
+            .iter()
+            .map(|dsn| Dsn {
+                secret: dsn.clone(),
+            })
+            .collect()
+    }
 }
 
 impl From<Dsn> for String {
@@ -30,15 +56,85 @@ impl From<Dsn> for String {
     }
 }
 
-/// An implementation of datastore::Client backed by Postgres
+/// How many operations may queue for a DB thread, per connection in the pool, before new ones are
+/// rejected.
+const QUEUED_PER_CONNECTION: usize = 8;
+
+type ConnPool = Pool<ConnectionManager<PgConnection>>;
+
+/// A pool of connections to one database, and the executor which runs queries with them.
+#[derive(Clone)]
+struct Db {
+    /// Labels this database's metrics, e.g. "primary" or "replica0".
+    name: String,
+    /// Taken by `close`, so that every clone of the store stops using the pool, and its
+    /// connections are closed once running queries return them.
+    pool: Arc<RwLock<Option<ConnPool>>>,
+    /// Runs queries, with one thread per pooled connection.
+    executor: Executor,
+    breaker: Breaker,
+}
+
+impl Db {
+    fn new(name: String, pool: ConnPool) -> Result<Self, anyhow::Error> {
+        let threads = pool.max_size();
+        let executor = Executor::new(&name, threads, threads as usize * QUEUED_PER_CONNECTION)?;
+        Ok(Self {
+            breaker: Breaker::new(&name),
+            name,
+            pool: Arc::new(RwLock::new(Some(pool))),
+            executor,
+        })
+    }
+
+    /// The pool, unless the store has been closed.
+    fn pool(&self) -> Option<ConnPool> {
+        self.pool.read().expect("pool lock poisoned").clone()
+    }
+
+    fn connections_in_use(&self) -> u32 {
+        self.pool().map_or(0, |pool| {
+            let state = pool.state();
+            state.connections - state.idle_connections
+        })
+    }
+
+    /// Stop the executor's threads and drop the pool. Returns how many connections were idle,
+    /// which are closed now. Connections still in use are closed when they're returned.
+    fn close(&self) -> u32 {
+        self.executor.close();
+        let pool = self.pool.write().expect("pool lock poisoned").take();
+        pool.map_or(0, |pool| pool.state().idle_connections)
+    }
+}
+
+/// An implementation of datastore::Datastore backed by Postgres. Writes go to the primary, and
+/// reads go to a read replica if there are any.
 #[derive(Clone)]
 pub struct PostgresStore {
-    pool: Pool<ConnectionManager<PgConnection>>,
-    idle_conns: IntGauge,
-    conns: IntGauge,
+    primary: Db,
+    replicas: Replicas,
+    slow_queries: Option<Arc<SlowQueryLog>>,
+    idle_conns: IntGaugeVec,
+    conns: IntGaugeVec,
 }
 
 impl PostgresStore {
+    /// A store whose database can't be reached, for testing how failures are handled.
+    #[cfg(test)]
+    pub fn unreachable() -> Self {
+        let manager = ConnectionManager::<PgConnection>::new("postgres://127.0.0.1:1/unreachable");
+        let pool = Pool::builder().min_idle(Some(0)).build_unchecked(manager);
+        let gauge = |name| IntGaugeVec::new(Opts::new(name, name), &["pool"]).unwrap();
+        Self {
+            primary: Db::new("unreachable".to_owned(), pool).unwrap(),
+            replicas: Replicas::default(),
+            slow_queries: None,
+            idle_conns: gauge("idle"),
+            conns: gauge("conns"),
+        }
+    }
+
     pub fn new(
         dsn: Dsn,
         max_pool_size: u32,
@@ -49,20 +145,160 @@ impl PostgresStore {
             .max_size(max_pool_size)
             .connection_timeout(conn_timeout)
             .build(manager)?;
-        let idle_conns = IntGauge::with_opts(Opts::new(
-            "quietbackend_db_connections_idle",
-            "How many DB connections are currently idle",
-        ))?;
-        let conns = IntGauge::with_opts(Opts::new(
-            "quietbackend_db_connections",
-            "How many DB connections are open",
-        ))?;
+        let idle_conns = IntGaugeVec::new(
+            Opts::new(
+                "quietbackend_db_connections_idle",
+                "How many DB connections are currently idle",
+            ),
+            &["pool"],
+        )?;
+        let conns = IntGaugeVec::new(
+            Opts::new(
+                "quietbackend_db_connections",
+                "How many DB connections are open",
+            ),
+            &["pool"],
+        )?;
         Ok(Self {
-            pool,
+            primary: Db::new("primary".to_owned(), pool)?,
+            replicas: Replicas::default(),
+            slow_queries: None,
             idle_conns,
             conns,
         })
     }
+
+    /// A store for the configured primary and replicas, with the slow query log if it's enabled.
+    pub fn from_config(config: &Config) -> Result<Self, anyhow::Error> {
+        let conn_timeout = Duration::from_secs(config.db_connection_timeout);
+        let store = Self::new(Dsn::new(config), config.db_pool_size, conn_timeout)?.with_replicas(
+            Dsn::replicas(config),
+            config.db_pool_size,
+            conn_timeout,
+        )?;
+        let threshold_ms = match config.slow_query_threshold_ms {
+            Some(threshold_ms) => threshold_ms,
+            None => return Ok(store),
+        };
+        let log = SlowQueryLog::new(Duration::from_millis(threshold_ms));
+        let log = match config.slow_query_plan_file.clone() {
+            Some(path) => log
+                .with_plans(path, config.slow_query_plan_sample_rate)
+                .context("couldn't open slow_query_plan_file")?,
+            None => log,
+        };
+        Ok(store.with_slow_query_log(log))
+    }
+
+    /// Send reads to these read replicas. Each gets its own pool, like the primary's. Unlike the
+    /// primary, replicas needn't be up yet: until they are, reads fall back to the primary.
+    pub fn with_replicas(
+        mut self,
+        dsns: Vec<Dsn>,
+        max_pool_size: u32,
+        conn_timeout: Duration,
+    ) -> Result<Self, anyhow::Error> {
+        let mut replicas = Vec::new();
+        for (i, dsn) in dsns.into_iter().enumerate() {
+            let manager = ConnectionManager::<PgConnection>::new(dsn);
+            let pool = Pool::builder()
+                .max_size(max_pool_size)
+                .connection_timeout(conn_timeout)
+                .build_unchecked(manager);
+            replicas.push(Replica::new(Db::new(format!("replica{}", i), pool)?));
+        }
+        self.replicas = Replicas::new(replicas);
+        Ok(self)
+    }
+
+    /// Log operations whose queries are slow.
+    pub fn with_slow_query_log(mut self, log: SlowQueryLog) -> Self {
+        self.slow_queries = Some(Arc::new(log));
+        self
+    }
+
+    fn dbs(&self) -> impl Iterator<Item = &Db> {
+        std::iter::once(&self.primary).chain(self.replicas.all.iter().map(|replica| &replica.db))
+    }
+}
+
+/// How many connections the pool has, and how many are free.
+#[derive(Debug, Serialize)]
+pub struct PoolState {
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub max_size: u32,
+}
+
+impl PostgresStore {
+    /// The state of the primary's pool.
+    pub fn pool_state(&self) -> PoolState {
+        match self.primary.pool() {
+            Some(pool) => {
+                let state = pool.state();
+                PoolState {
+                    connections: state.connections,
+                    idle_connections: state.idle_connections,
+                    max_size: pool.max_size(),
+                }
+            }
+            None => PoolState {
+                connections: 0,
+                idle_connections: 0,
+                max_size: 0,
+            },
+        }
+    }
+
+    /// Check out a connection to the primary and run a trivial query, giving up after `timeout`.
+    /// Replicas aren't checked, since reads can fall back to the primary.
+    pub async fn ping(&self, timeout: Duration) -> Result<(), anyhow::Error> {
+        let pool = self
+            .primary
+            .pool()
+            .ok_or_else(|| anyhow!("DB connections are closed"))?;
+        let ping = self
+            .primary
+            .executor
+            .run(move || -> Result<(), anyhow::Error> {
+                let conn = pool.get_timeout(timeout)?;
+                diesel::sql_query("SELECT 1").execute(&conn)?;
+                Ok(())
+            });
+        match actix_rt::time::timeout(timeout, ping).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(ExecutorError::Busy)) => Err(anyhow!("DB queue is full")),
+            Ok(Err(ExecutorError::Lost)) => Err(anyhow!("DB operation panicked")),
+            Ok(Err(ExecutorError::Closed)) => Err(anyhow!("DB connections are closed")),
+            Err(_) => Err(anyhow!("timed out after {}ms", timeout.as_millis())),
+        }
+    }
+
+    /// Wait until every connection has been returned to its pool, i.e. no query or transaction is
+    /// still running, so that none is cut off when the process exits. Then stop the executors and
+    /// drop the pools, the primary's and the replicas'. Operations fail once the store is closed.
+    pub async fn close(&self, deadline: Instant) -> Closed {
+        let busy_connections = loop {
+            let in_use = self.dbs().map(Db::connections_in_use).sum();
+            if in_use == 0 || Instant::now() >= deadline {
+                break in_use;
+            }
+            actix_rt::time::delay_for(Duration::from_millis(50)).await;
+        };
+        Closed {
+            closed_connections: self.dbs().map(Db::close).sum(),
+            busy_connections,
+        }
+    }
+}
+
+/// What happened to the store's connections when it was closed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Closed {
+    /// Idle connections, which were closed.
+    pub closed_connections: u32,
+    /// Connections which were still in use when the deadline passed.
+    pub busy_connections: u32,
 }
 
 impl Collector for PostgresStore {
@@ -73,11 +309,48 @@ impl Collector for PostgresStore {
     }
 
     fn collect(&self) -> Vec<MetricFamily> {
-        self.idle_conns
-            .set(self.pool.state().idle_connections as i64);
-        self.conns.set(self.pool.state().connections as i64);
+        for db in self.dbs() {
+            // A closed pool has no connections
+            let (conns, idle) = db.pool().map_or((0, 0), |pool| {
+                let state = pool.state();
+                (state.connections, state.idle_connections)
+            });
+            let labels = [db.name.as_str()];
+            self.idle_conns.with_label_values(&labels).set(idle as i64);
+            self.conns.with_label_values(&labels).set(conns as i64);
+        }
         let mut metrics = self.idle_conns.collect();
         metrics.extend(self.conns.collect());
         metrics
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::Datastore;
+    use crate::deadline;
+    use crate::twoface::Cause;
+
+    #[actix_rt::test]
+    async fn test_closed_store_rejects_operations() {
+        let db = PostgresStore::unreachable();
+        let closed = db.close(Instant::now()).await;
+        assert_eq!(closed, Closed::default());
+        assert!(db.ping(Duration::from_millis(100)).await.is_err());
+        let err = db.get_user(uuid::Uuid::new_v4()).await.unwrap_err();
+        assert!(matches!(err.external.cause, Cause::ServiceUnavailable));
+        assert_eq!(db.pool_state().max_size, 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_probe_past_its_deadline_doesnt_block_the_next() {
+        let db = PostgresStore::unreachable();
+        db.primary.breaker.open_until(Instant::now());
+        let passed = Instant::now() - Duration::from_millis(1);
+        let get_user = db.get_user(uuid::Uuid::new_v4());
+        let err = deadline::scope(passed, get_user).await.unwrap_err();
+        assert!(matches!(err.external.cause, Cause::ServiceUnavailable));
+        assert!(db.primary.breaker.allow().is_some());
+    }
+}
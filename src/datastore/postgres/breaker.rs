@@ -0,0 +1,207 @@
+//! A circuit breaker, so that when a database is down, operations fail fast instead of each waiting
+//! for a connection until they time out.
+use crate::metrics;
+use prometheus::{IntCounter, IntGauge};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How many operations in a row must fail to connect before the breaker opens.
+const FAILURES_TO_OPEN: u32 = 5;
+
+/// How long the breaker stays open before letting an operation through to probe the database.
+const OPEN_FOR: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Operations run. Counts consecutive connection failures.
+    Closed { failures: u32 },
+    /// Operations are rejected until the given time.
+    Open { until: Instant },
+    /// One operation is probing the database. Others are rejected until it finishes, or until the
+    /// given time if it never does.
+    HalfOpen { until: Instant },
+}
+
+impl State {
+    /// For the state gauge.
+    fn metric_value(self) -> i64 {
+        match self {
+            State::Closed { .. } => 0,
+            State::Open { .. } => 1,
+            State::HalfOpen { .. } => 2,
+        }
+    }
+}
+
+/// Tracks whether one database is reachable.
+#[derive(Clone)]
+pub struct Breaker {
+    name: String,
+    state: Arc<Mutex<State>>,
+    state_gauge: IntGauge,
+    rejections: IntCounter,
+}
+
+impl Breaker {
+    pub fn new(name: &str) -> Self {
+        let state = State::Closed { failures: 0 };
+        let state_gauge = metrics::DB_BREAKER_STATE.with_label_values(&[name]);
+        state_gauge.set(state.metric_value());
+        Self {
+            name: name.to_owned(),
+            state: Arc::new(Mutex::new(state)),
+            state_gauge,
+            rejections: metrics::DB_BREAKER_REJECTIONS.with_label_values(&[name]),
+        }
+    }
+
+    /// Whether an operation may run now. If so, it should `record` whether it could connect.
+    pub fn allow(&self) -> Option<Attempt<'_>> {
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("breaker lock poisoned");
+        match *state {
+            State::Closed { .. } => {}
+            State::Open { until } | State::HalfOpen { until } if now < until => {
+                self.rejections.inc();
+                return None;
+            }
+            State::Open { .. } | State::HalfOpen { .. } => {
+                self.set(
+                    &mut state,
+                    State::HalfOpen {
+                        until: now + OPEN_FOR,
+                    },
+                );
+            }
+        }
+        Some(Attempt {
+            breaker: self,
+            recorded: false,
+        })
+    }
+
+    fn record(&self, connected: bool) {
+        let mut state = self.state.lock().expect("breaker lock poisoned");
+        let next = match (*state, connected) {
+            (State::Closed { failures: 0 }, true) => return,
+            (State::Closed { .. }, true) => State::Closed { failures: 0 },
+            (_, true) => {
+                info!(db = &self.name[..], "DB circuit breaker closed");
+                State::Closed { failures: 0 }
+            }
+            (State::Closed { failures }, false) if failures + 1 < FAILURES_TO_OPEN => {
+                State::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (_, false) => {
+                warn!(db = &self.name[..], "DB circuit breaker opened");
+                State::Open {
+                    until: Instant::now() + OPEN_FOR,
+                }
+            }
+        };
+        self.set(&mut state, next);
+    }
+
+    /// The operation ended without showing whether the database is reachable. If it was
+    /// probing, let the next operation probe instead of waiting out another `OPEN_FOR`.
+    fn record_unknown(&self) {
+        let mut state = self.state.lock().expect("breaker lock poisoned");
+        if let State::HalfOpen { .. } = *state {
+            self.set(
+                &mut state,
+                State::Open {
+                    until: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// Open the breaker until `until`, as if the database had been failing.
+    #[cfg(test)]
+    pub fn open_until(&self, until: Instant) {
+        let mut state = self.state.lock().expect("breaker lock poisoned");
+        self.set(&mut state, State::Open { until });
+    }
+
+    fn set(&self, state: &mut State, next: State) {
+        *state = next;
+        self.state_gauge.set(next.metric_value());
+    }
+}
+
+/// An operation which the breaker let through. If it's dropped without being recorded (e.g. the
+/// operation was rejected by its executor, or cancelled), its outcome is unknown.
+pub struct Attempt<'a> {
+    breaker: &'a Breaker,
+    recorded: bool,
+}
+
+impl Attempt<'_> {
+    /// Record whether the operation could reach the database.
+    pub fn record(mut self, connected: bool) {
+        self.recorded = true;
+        self.breaker.record(connected);
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.record_unknown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opens_after_failures() {
+        let breaker = Breaker::new("test_breaker");
+        for _ in 0..FAILURES_TO_OPEN - 1 {
+            breaker.allow().unwrap().record(false);
+        }
+        // A success resets the count
+        breaker.allow().unwrap().record(true);
+        for _ in 0..FAILURES_TO_OPEN {
+            breaker.allow().unwrap().record(false);
+        }
+        assert!(breaker.allow().is_none());
+        assert_eq!(breaker.state_gauge.get(), 1);
+        assert_eq!(breaker.rejections.get(), 1);
+
+        // Once it's been open long enough, one operation probes the database
+        let past = Instant::now() - Duration::from_secs(1);
+        *breaker.state.lock().unwrap() = State::Open { until: past };
+        let probe = breaker.allow().unwrap();
+        assert!(breaker.allow().is_none());
+        assert_eq!(breaker.state_gauge.get(), 2);
+        probe.record(false);
+        assert!(breaker.allow().is_none());
+
+        *breaker.state.lock().unwrap() = State::Open { until: past };
+        breaker.allow().unwrap().record(true);
+        assert!(breaker.allow().is_some());
+        assert_eq!(breaker.state_gauge.get(), 0);
+    }
+
+    #[test]
+    fn test_unfinished_probe_doesnt_block_the_next() {
+        let breaker = Breaker::new("test_breaker_unfinished");
+        let past = Instant::now() - Duration::from_secs(1);
+        *breaker.state.lock().unwrap() = State::Open { until: past };
+        // e.g. the probe's deadline passed before it could check out a connection
+        let probe = breaker.allow().unwrap();
+        assert!(breaker.allow().is_none());
+        drop(probe);
+        assert_eq!(breaker.state_gauge.get(), 1);
+        let probe = breaker.allow().unwrap();
+        assert_eq!(breaker.state_gauge.get(), 2);
+        probe.record(true);
+        assert_eq!(breaker.state_gauge.get(), 0);
+    }
+}
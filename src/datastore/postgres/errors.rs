@@ -1,26 +1,110 @@
-use crate::twoface::{ExternalError, Fallible, TfError};
-use actix_web::error::BlockingError;
+use crate::deadline;
+use crate::twoface::{Cause, ExternalError, Fallible, TfError};
 use anyhow::anyhow;
-use diesel::result::Error as DieselError;
+use diesel::r2d2::PoolError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError, QueryResult};
 
-type DbPoolErr = BlockingError<DieselError>;
-pub type DbPoolResult<T> = Result<T, DbPoolErr>;
+/// Why an operation didn't get a result from its query.
+pub enum RunError {
+    /// The database's queue was full.
+    Busy,
+    /// The database's circuit breaker is open, since it seems to be down.
+    BreakerOpen,
+    /// No connection could be checked out of its pool.
+    NoConnection(PoolError),
+    /// The request's deadline passed before the query could start.
+    DeadlineExceeded,
+    /// The operation panicked.
+    Lost,
+    /// The store was closed, because the server is shutting down.
+    Closed,
+}
 
-/// Convenience extension used to extract errors from `web::block`.
-pub trait BlockingResp<T> {
-    /// Convert the return from a web::block into a normal `Fallible<T>`.
+/// Convenience extension used to extract errors from an attempt to run an operation.
+pub trait AttemptResp<T> {
+    /// Convert the result of the attempt into a normal `Fallible<T>`.
     fn to_resp(self) -> Fallible<T>;
 }
 
-impl<T, I: std::fmt::Debug + Into<TfError>> BlockingResp<T> for Result<T, BlockingError<I>> {
+impl<T> AttemptResp<T> for Result<QueryResult<T>, RunError> {
     fn to_resp(self) -> Fallible<T> {
         match self {
-            Ok(t) => Ok(t),
-            Err(BlockingError::Error(err)) => Err(err.into()),
-            Err(BlockingError::Canceled) => Err(TfError {
-                internal: anyhow!("DB operation cancelled"),
+            Ok(Err(e)) if error_class(&e) == "statement_timeout" => {
+                Err(deadline::exceeded(e.into()))
+            }
+            Ok(result) => result.map_err(Into::into),
+            Err(RunError::Busy) => Err(TfError {
+                internal: anyhow!("DB queue is full"),
+                external: ExternalError {
+                    cause: Cause::ServiceUnavailable,
+                    text: "The server is too busy right now, please try again later".into(),
+                },
+            }),
+            Err(RunError::BreakerOpen) => Err(TfError {
+                internal: anyhow!("DB circuit breaker is open"),
+                external: ExternalError {
+                    cause: Cause::ServiceUnavailable,
+                    text: "The database is unavailable right now, please try again later".into(),
+                },
+            }),
+            Err(RunError::NoConnection(e)) => Err(e.into()),
+            Err(RunError::DeadlineExceeded) => Err(deadline::exceeded(anyhow!(
+                "request deadline passed before the DB operation started"
+            ))),
+            Err(RunError::Closed) => Err(TfError {
+                internal: anyhow!("DB connections are closed"),
+                external: ExternalError {
+                    cause: Cause::ServiceUnavailable,
+                    text: "The server is shutting down, please try again".into(),
+                },
+            }),
+            Err(RunError::Lost) => Err(TfError {
+                internal: anyhow!("DB operation panicked"),
                 external: ExternalError::default(),
             }),
         }
     }
 }
+
+/// A coarse, low-cardinality description of a query error, for metrics.
+pub fn error_class(err: &DieselError) -> &'static str {
+    match err {
+        DieselError::NotFound => "not_found",
+        DieselError::DatabaseError(kind, info) => match kind {
+            DatabaseErrorKind::UniqueViolation => "unique_violation",
+            DatabaseErrorKind::ForeignKeyViolation => "foreign_key_violation",
+            DatabaseErrorKind::SerializationFailure => "serialization_failure",
+            DatabaseErrorKind::UnableToSendCommand => "connection",
+            // Diesel doesn't give other SQLSTATEs a kind, so spot statement timeouts, and
+            // connections being dropped (e.g. during a failover), by their message.
+            _ if info
+                .message()
+                .contains("canceling statement due to statement timeout") =>
+            {
+                "statement_timeout"
+            }
+            _ if info.message().contains("terminating connection")
+                || info.message().contains("server closed the connection") =>
+            {
+                "connection"
+            }
+            _ => "database",
+        },
+        DieselError::DeserializationError(_) | DieselError::SerializationError(_) => {
+            "serialization"
+        }
+        DieselError::RollbackTransaction | DieselError::AlreadyInTransaction => "transaction",
+        _ => "other",
+    }
+}
+
+/// Whether a query failed because the database couldn't be reached, rather than because of the
+/// query itself.
+pub fn is_connection_error(err: &DieselError) -> bool {
+    error_class(err) == "connection"
+}
+
+/// Whether a query which failed might succeed if it were simply run again.
+pub fn is_transient(err: &DieselError) -> bool {
+    matches!(error_class(err), "connection" | "serialization_failure")
+}
@@ -0,0 +1,169 @@
+//! A dedicated pool of threads for running blocking Diesel queries, with a bounded queue.
+//!
+//! Actix's shared blocking threadpool queues work without limit, so under load requests pile up
+//! behind it until they fail with a vague error. Here, once the queue is full new work is rejected
+//! straight away, so that callers can shed load with a 503 rather than waiting and timing out.
+use crate::metrics;
+use futures::channel::oneshot;
+use prometheus::{IntCounter, IntGauge};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs blocking work on its own threads.
+#[derive(Clone)]
+pub struct Executor {
+    /// Taken by `close`, which stops the threads once they've run every queued job.
+    jobs: Arc<Mutex<Option<SyncSender<Job>>>>,
+    queued: IntGauge,
+    rejected: IntCounter,
+}
+
+/// Why work didn't run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutorError {
+    /// The queue was full, so the work was rejected.
+    Busy,
+    /// The work was accepted but never finished, because it panicked.
+    Lost,
+    /// The executor was closed, so the work was rejected.
+    Closed,
+}
+
+impl Executor {
+    /// Start `threads` worker threads, which share a queue of up to `capacity` jobs. `name`
+    /// labels the executor's threads and metrics. Threads stop once the executor is closed, or
+    /// every clone of it has been dropped.
+    pub fn new(name: &str, threads: u32, capacity: usize) -> std::io::Result<Self> {
+        let (jobs, receiver) = sync_channel::<Job>(capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queued = metrics::DB_QUEUE_DEPTH.with_label_values(&[name]);
+        for i in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            let queued = queued.clone();
+            thread::Builder::new()
+                .name(format!("db-{}-{}", name, i))
+                .spawn(move || work(&receiver, &queued))?;
+        }
+        Ok(Self {
+            jobs: Arc::new(Mutex::new(Some(jobs))),
+            queued,
+            rejected: metrics::DB_QUEUE_REJECTIONS.with_label_values(&[name]),
+        })
+    }
+
+    /// Run `f` on one of the executor's threads, unless the queue is full.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, ExecutorError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let job = Box::new(move || {
+            // If the caller stopped waiting (e.g. its request was cancelled), skip the work.
+            if !sender.is_canceled() {
+                let _ = sender.send(f());
+            }
+        });
+        self.queued.inc();
+        let sent = match &*self.lock() {
+            Some(jobs) => jobs.try_send(job),
+            None => {
+                self.queued.dec();
+                return Err(ExecutorError::Closed);
+            }
+        };
+        if let Err(e) = sent {
+            self.queued.dec();
+            match e {
+                TrySendError::Full(_) => {
+                    self.rejected.inc();
+                    return Err(ExecutorError::Busy);
+                }
+                TrySendError::Disconnected(_) => unreachable!("workers outlive the executor"),
+            }
+        }
+        receiver.await.map_err(|_| ExecutorError::Lost)
+    }
+
+    /// Reject new work, for every clone of the executor. Work which is already queued still runs,
+    /// then the threads stop.
+    pub fn close(&self) {
+        self.lock().take();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<SyncSender<Job>>> {
+        self.jobs.lock().expect("executor sender lock poisoned")
+    }
+}
+
+fn work(jobs: &Mutex<Receiver<Job>>, queued: &IntGauge) {
+    loop {
+        // Only hold the lock while waiting for a job, not while running it.
+        let job = match jobs.lock().expect("executor queue lock poisoned").recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        queued.dec();
+        // A panicking job drops its result sender, so its caller gets `Lost`. Carry on with the
+        // next job, rather than losing a thread.
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{pin_mut, poll};
+    use std::sync::mpsc::channel;
+
+    #[actix_rt::test]
+    async fn test_rejects_when_full() {
+        let executor = Executor::new("test", 1, 1).unwrap();
+        assert_eq!(executor.run(|| 1 + 1).await, Ok(2));
+        assert_eq!(
+            executor.run(|| panic!("query failed")).await,
+            Err::<(), _>(ExecutorError::Lost)
+        );
+
+        // Occupy the only thread, then fill the queue
+        let (release, wait) = channel::<()>();
+        let (started, has_started) = channel();
+        let blocked = executor.run(move || {
+            started.send(()).unwrap();
+            wait.recv().unwrap();
+        });
+        pin_mut!(blocked);
+        assert!(poll!(&mut blocked).is_pending());
+        has_started.recv().unwrap();
+        let queued = executor.run(|| "ran");
+        pin_mut!(queued);
+        assert!(poll!(&mut queued).is_pending());
+        assert_eq!(executor.queued.get(), 1);
+        assert_eq!(executor.run(|| ()).await, Err(ExecutorError::Busy));
+        assert_eq!(executor.rejected.get(), 1);
+
+        release.send(()).unwrap();
+        assert_eq!(blocked.await, Ok(()));
+        assert_eq!(queued.await, Ok("ran"));
+        assert_eq!(executor.queued.get(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_close() {
+        let executor = Executor::new("test_close", 1, 1).unwrap();
+        let (release, wait) = channel::<()>();
+        let blocked = executor.run(move || wait.recv().unwrap());
+        pin_mut!(blocked);
+        assert!(poll!(&mut blocked).is_pending());
+
+        // Work which was accepted before closing still runs
+        executor.clone().close();
+        assert_eq!(executor.run(|| ()).await, Err(ExecutorError::Closed));
+        release.send(()).unwrap();
+        assert_eq!(blocked.await, Ok(()));
+    }
+}
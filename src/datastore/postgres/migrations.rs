@@ -0,0 +1,304 @@
+//! Schema migrations, embedded from the goose files in `deployment/db_migrations`. Which have been
+//! applied is tracked in goose's `goose_db_version` table, so the goose CLI can still be used too.
+use super::Dsn;
+use anyhow::{anyhow, bail, Context};
+use chrono::NaiveDateTime;
+use diesel::{
+    connection::SimpleConnection,
+    pg::PgConnection,
+    sql_types::{BigInt, Bool, Nullable, Timestamp},
+    Connection, RunQueryDsl,
+};
+use std::collections::BTreeMap;
+use std::time::Instant;
+use tracing::info;
+
+/// Every migration file, in order.
+const SOURCES: &[(&str, &str)] = &[
+    (
+        "20200907213827_posts.sql",
+        include_str!("../../../deployment/db_migrations/20200907213827_posts.sql"),
+    ),
+    (
+        "20201018120000_match_tables.sql",
+        include_str!("../../../deployment/db_migrations/20201018120000_match_tables.sql"),
+    ),
+];
+
+/// Session-level advisory lock held while migrating, so that instances starting together don't
+/// migrate at the same time. The value is arbitrary, it just mustn't clash with other locks.
+const LOCK_KEY: i64 = 0x7175_6965_745f_6d67;
+
+/// One migration file.
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    up: String,
+    down: String,
+    /// Whether it runs in a transaction. Goose files opt out with `-- +goose NO TRANSACTION`.
+    transaction: bool,
+}
+
+impl Migration {
+    fn parse(name: &'static str, source: &str) -> Result<Self, anyhow::Error> {
+        let version = name
+            .split('_')
+            .next()
+            .and_then(|version| version.parse().ok())
+            .ok_or_else(|| anyhow!("{} doesn't start with a version number", name))?;
+        let (mut up, mut down) = (None, None);
+        let mut section: Option<&mut String> = None;
+        let mut transaction = true;
+        for line in source.lines() {
+            match line.trim() {
+                "-- +goose Up" => section = Some(up.get_or_insert_with(String::new)),
+                "-- +goose Down" => section = Some(down.get_or_insert_with(String::new)),
+                "-- +goose NO TRANSACTION" => transaction = false,
+                _ => {
+                    if let Some(sql) = section.as_mut() {
+                        sql.push_str(line);
+                        sql.push('\n');
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            version,
+            name,
+            up: up.ok_or_else(|| anyhow!("{} has no `-- +goose Up` section", name))?,
+            down: down.unwrap_or_default(),
+            transaction,
+        })
+    }
+}
+
+/// Every embedded migration, in order.
+pub fn embedded() -> Vec<Migration> {
+    SOURCES
+        .iter()
+        .map(|(name, source)| Migration::parse(name, source).expect("migrations are valid"))
+        .collect()
+}
+
+/// Whether a migration has been applied.
+#[derive(Debug)]
+pub struct Status {
+    pub version: i64,
+    /// None if the database has a version this binary doesn't know about, e.g. because a newer
+    /// binary migrated it.
+    pub name: Option<&'static str>,
+    pub applied_at: Option<NaiveDateTime>,
+    pub applied: bool,
+}
+
+#[derive(QueryableByName)]
+struct VersionRow {
+    #[sql_type = "BigInt"]
+    version_id: i64,
+    #[sql_type = "Bool"]
+    is_applied: bool,
+    #[sql_type = "Nullable<Timestamp>"]
+    tstamp: Option<NaiveDateTime>,
+}
+
+/// Applies migrations. Only one instance can have a `Migrator` at a time.
+pub struct Migrator {
+    conn: PgConnection,
+    migrations: Vec<Migration>,
+}
+
+impl Migrator {
+    /// Connect to the database, waiting for any other instance to finish migrating it.
+    pub fn connect(dsn: Dsn) -> Result<Self, anyhow::Error> {
+        let conn = PgConnection::establish(&String::from(dsn))?;
+        info!("waiting for the migration lock");
+        // Released when the connection closes.
+        diesel::sql_query(format!("SELECT pg_advisory_lock({})", LOCK_KEY)).execute(&conn)?;
+        conn.batch_execute(
+            "CREATE TABLE IF NOT EXISTS goose_db_version (
+                id serial NOT NULL,
+                version_id bigint NOT NULL,
+                is_applied boolean NOT NULL,
+                tstamp timestamp NULL DEFAULT now(),
+                PRIMARY KEY (id)
+            );
+            INSERT INTO goose_db_version (version_id, is_applied)
+                SELECT 0, true WHERE NOT EXISTS (SELECT 1 FROM goose_db_version);",
+        )?;
+        Ok(Self {
+            conn,
+            migrations: embedded(),
+        })
+    }
+
+    /// When each applied version was applied. Goose records rollbacks as well as migrations, so
+    /// each version's latest row says whether it's applied.
+    fn applied(&self) -> Result<BTreeMap<i64, Option<NaiveDateTime>>, anyhow::Error> {
+        let rows: Vec<VersionRow> = diesel::sql_query(
+            "SELECT version_id, is_applied, tstamp FROM goose_db_version ORDER BY id DESC",
+        )
+        .load(&self.conn)?;
+        let mut latest = BTreeMap::new();
+        for row in rows.into_iter().filter(|row| row.version_id != 0) {
+            latest.entry(row.version_id).or_insert(row);
+        }
+        Ok(latest
+            .into_iter()
+            .filter(|(_, row)| row.is_applied)
+            .map(|(version, row)| (version, row.tstamp))
+            .collect())
+    }
+
+    /// Every known migration and every applied version, in order.
+    pub fn status(&self) -> Result<Vec<Status>, anyhow::Error> {
+        let applied = self.applied()?;
+        let mut statuses: BTreeMap<_, _> = self
+            .migrations
+            .iter()
+            .map(|migration| {
+                let status = Status {
+                    version: migration.version,
+                    name: Some(migration.name),
+                    applied_at: applied.get(&migration.version).copied().flatten(),
+                    applied: applied.contains_key(&migration.version),
+                };
+                (migration.version, status)
+            })
+            .collect();
+        for (&version, &applied_at) in &applied {
+            statuses.entry(version).or_insert(Status {
+                version,
+                name: None,
+                applied_at,
+                applied: true,
+            });
+        }
+        Ok(statuses.into_values().collect())
+    }
+
+    /// Apply every pending migration, in order. Returns the names of those applied.
+    pub fn up(&self) -> Result<Vec<&'static str>, anyhow::Error> {
+        let applied = self.applied()?;
+        let mut names = Vec::new();
+        for migration in &self.migrations {
+            if !applied.contains_key(&migration.version) {
+                self.apply(migration, true)?;
+                names.push(migration.name);
+            }
+        }
+        if names.is_empty() {
+            info!("the database is up to date");
+        }
+        Ok(names)
+    }
+
+    /// Roll back the latest applied migration, if there is one. Returns its name.
+    pub fn down(&self) -> Result<Option<&'static str>, anyhow::Error> {
+        let migration = match self.latest()? {
+            Some(migration) => migration,
+            None => return Ok(None),
+        };
+        self.apply(migration, false)?;
+        Ok(Some(migration.name))
+    }
+
+    /// Roll back the latest applied migration and apply it again. Returns its name.
+    pub fn redo(&self) -> Result<Option<&'static str>, anyhow::Error> {
+        let migration = match self.latest()? {
+            Some(migration) => migration,
+            None => return Ok(None),
+        };
+        self.apply(migration, false)?;
+        self.apply(migration, true)?;
+        Ok(Some(migration.name))
+    }
+
+    fn latest(&self) -> Result<Option<&Migration>, anyhow::Error> {
+        let version = match self.applied()?.keys().next_back() {
+            Some(&version) => version,
+            None => return Ok(None),
+        };
+        match self.migrations.iter().find(|m| m.version == version) {
+            Some(migration) => Ok(Some(migration)),
+            None => bail!(
+                "the latest applied version, {}, isn't a migration this binary knows",
+                version
+            ),
+        }
+    }
+
+    fn apply(&self, migration: &Migration, up: bool) -> Result<(), anyhow::Error> {
+        let direction = if up { "up" } else { "down" };
+        let started = Instant::now();
+        let run = || -> diesel::QueryResult<()> {
+            if up {
+                self.conn.batch_execute(&migration.up)?;
+                diesel::sql_query(
+                    "INSERT INTO goose_db_version (version_id, is_applied) VALUES ($1, true)",
+                )
+                .bind::<BigInt, _>(migration.version)
+                .execute(&self.conn)?;
+            } else {
+                self.conn.batch_execute(&migration.down)?;
+                diesel::sql_query("DELETE FROM goose_db_version WHERE version_id = $1")
+                    .bind::<BigInt, _>(migration.version)
+                    .execute(&self.conn)?;
+            }
+            Ok(())
+        };
+        let result = if migration.transaction {
+            self.conn.transaction(run)
+        } else {
+            run()
+        };
+        result.with_context(|| format!("couldn't migrate {} {}", direction, migration.name))?;
+        info!(
+            migration = migration.name,
+            direction,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "migrated"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_migration_is_embedded() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/deployment/db_migrations");
+        let mut files: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".sql"))
+            .collect();
+        files.sort();
+        let embedded: Vec<_> = embedded().iter().map(|m| m.name.to_owned()).collect();
+        assert_eq!(embedded, files);
+    }
+
+    #[test]
+    fn test_parse() {
+        let migration = Migration::parse(
+            "42_test.sql",
+            "-- comment\n-- +goose Up\nCREATE TABLE t ();\n-- +goose Down\nDROP TABLE t;\n",
+        )
+        .unwrap();
+        assert_eq!(migration.version, 42);
+        assert_eq!(migration.up, "CREATE TABLE t ();\n");
+        assert_eq!(migration.down, "DROP TABLE t;\n");
+        assert!(migration.transaction);
+
+        let migration = Migration::parse(
+            "43_test.sql",
+            "-- +goose NO TRANSACTION\n-- +goose Up\nCREATE INDEX CONCURRENTLY i ON t (c);\n",
+        )
+        .unwrap();
+        assert!(!migration.transaction);
+        assert!(Migration::parse("test.sql", "-- +goose Up\n").is_err());
+        assert!(Migration::parse("44_test.sql", "CREATE TABLE t ();\n").is_err());
+    }
+}
@@ -1,72 +1,390 @@
 use crate::datastore::{
+    memory::Snapshot,
     postfilters::PostFilters,
     postgres::{
-        errors::{BlockingResp, DbPoolResult},
-        PostgresStore,
+        errors::{error_class, is_connection_error, is_transient, AttemptResp, RunError},
+        executor::ExecutorError,
+        must_read_primary, slow_queries, Db, PostgresStore,
     },
-    structs::{NewPost, Post, User},
+    structs::{BusinessStats, Follow, NewPost, NewUser, Post, Purged, User, UserExport},
     tables::{follows, posts, users},
+    Datastore,
 };
-use crate::twoface::{Fallible, TfError};
-use actix_web::web::block;
+use crate::deadline;
+use crate::metrics::{self, openmetrics};
+use crate::telemetry::otlp::current_trace_id;
+use crate::twoface::Fallible;
+use async_trait::async_trait;
+use chrono::{offset::Utc, DateTime};
 use diesel::{
-    dsl::now,
-    expression::BoxableExpression,
-    expression_methods::BoolExpressionMethods,
-    pg::Pg,
-    query_dsl::{QueryDsl, RunQueryDsl},
-    sql_types::Bool,
-    BelongingToDsl, Connection, ExpressionMethods, JoinOnDsl, OptionalExtension,
-    TextExpressionMethods,
+    dsl::{now, sql},
+    pg::PgConnection,
+    query_dsl::{GroupByDsl, QueryDsl, RunQueryDsl},
+    sql_types::BigInt,
+    BelongingToDsl, BoolExpressionMethods, Connection, ExpressionMethods, JoinOnDsl,
+    OptionalExtension, QueryResult,
 };
+use prometheus::HistogramVec;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info_span, warn};
+use tracing_futures::Instrument;
 use uuid::Uuid;
 
+/// How many times to try a read which keeps failing with transient errors.
+const MAX_READ_ATTEMPTS: u32 = 3;
+
+/// Rows per statement in `bulk_insert`.
+const BULK_INSERT_ROWS: usize = 10_000;
+
+/// Retries wait for a random time up to this, which doubles with each retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(25);
+
+impl PostgresStore {
+    /// Run `query` on the primary.
+    async fn run<T, F>(&self, operation: &'static str, query: F) -> Fallible<T>
+    where
+        F: FnOnce(&PgConnection) -> QueryResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.try_run(&self.primary, operation, query)
+            .await
+            .to_resp()
+    }
+
+    /// Run a read-only `query`, retrying if it fails with a transient error (e.g. the connection
+    /// was dropped during a failover). Reads are idempotent, so they're safe to retry.
+    async fn read<T, F>(&self, operation: &'static str, query: F) -> Fallible<T>
+    where
+        F: Fn(&PgConnection) -> QueryResult<T> + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        let query = Arc::new(query);
+        let mut attempt = 1;
+        loop {
+            let result = self.read_once(operation, Arc::clone(&query)).await;
+            match &result {
+                Ok(Err(e)) if attempt < MAX_READ_ATTEMPTS && is_transient(e) => {
+                    metrics::DB_RETRIES
+                        .with_label_values(&[operation, error_class(e)])
+                        .inc();
+                }
+                _ => return result.to_resp(),
+            }
+            actix_rt::time::delay_for(backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Run a read-only `query` on a replica. It runs on the primary instead if there are no
+    /// replicas, none can run it, or the current request must see its own writes.
+    async fn read_once<T, F>(
+        &self,
+        operation: &'static str,
+        query: Arc<F>,
+    ) -> Result<QueryResult<T>, RunError>
+    where
+        F: Fn(&PgConnection) -> QueryResult<T> + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        if !self.replicas.all.is_empty() && !must_read_primary() {
+            let fallback_reason = match self.replicas.pick() {
+                Some(replica) => {
+                    let replica_query = Arc::clone(&query);
+                    let attempt =
+                        self.try_run(&replica.db, operation, move |conn| replica_query(conn));
+                    match attempt.await {
+                        Err(RunError::Busy) => "busy",
+                        Err(RunError::BreakerOpen) => "breaker_open",
+                        Err(RunError::NoConnection(e)) => {
+                            let name = &replica.db.name[..];
+                            warn!(replica = name, error = %e, "read replica is unavailable");
+                            replica.mark_unhealthy();
+                            "no_connection"
+                        }
+                        result => return result,
+                    }
+                }
+                None => "unhealthy",
+            };
+            metrics::DB_REPLICA_FALLBACKS
+                .with_label_values(&[fallback_reason])
+                .inc();
+        }
+        self.try_run(&self.primary, operation, move |conn| query(conn))
+            .await
+    }
+
+    /// Run `query` with a pooled connection on `db`'s executor, failing fast if its queue is full
+    /// or its circuit breaker is open. Records how long the operation queued for a thread, waited
+    /// for a connection and spent querying, and what kind of error (if any) it failed with, and
+    /// logs the query if it was slow.
+    async fn try_run<T, F>(
+        &self,
+        db: &Db,
+        operation: &'static str,
+        query: F,
+    ) -> Result<QueryResult<T>, RunError>
+    where
+        F: FnOnce(&PgConnection) -> QueryResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        // If the operation doesn't record whether it connected (e.g. it's cancelled), the
+        // breaker treats its outcome as unknown.
+        let attempt = match db.breaker.allow() {
+            Some(attempt) => attempt,
+            None => return Err(RunError::BreakerOpen),
+        };
+        let pool = db.pool().ok_or(RunError::Closed)?;
+        let name = db.name.clone();
+        let slow_queries = self.slow_queries.clone();
+        let deadline = deadline::current();
+        // The query runs on another thread, outside the current span, so find its trace now.
+        let trace_id = current_trace_id();
+        let queued_at = Instant::now();
+        let result = db
+            .executor
+            .run(move || {
+                let labels = [&name[..], operation];
+                let started = Instant::now();
+                observe_secs(&metrics::DB_QUEUE_SECS, &labels, queued_at, started);
+                // Don't wait for a connection after the request has given up.
+                let timeout = match deadline {
+                    Some(deadline) if deadline <= started => {
+                        return Err(RunError::DeadlineExceeded)
+                    }
+                    Some(deadline) => pool.connection_timeout().min(deadline - started),
+                    None => pool.connection_timeout(),
+                };
+                let conn = match pool.get_timeout(timeout) {
+                    Ok(conn) => conn,
+                    Err(_) if deadline.is_some_and(|deadline| deadline <= Instant::now()) => {
+                        return Err(RunError::DeadlineExceeded);
+                    }
+                    Err(e) => {
+                        metrics::DB_POOL_TIMEOUTS.with_label_values(&labels).inc();
+                        return Err(RunError::NoConnection(e));
+                    }
+                };
+                let checked_out = Instant::now();
+                observe_secs(&metrics::DB_POOL_WAIT_SECS, &labels, started, checked_out);
+                let run = || match deadline {
+                    Some(deadline) => with_statement_timeout(&conn, deadline, query),
+                    None => query(&conn),
+                };
+                let result = match &slow_queries {
+                    Some(log) => slow_queries::logging(log, operation, &name, run),
+                    None => run(),
+                };
+                let query_secs = checked_out.elapsed().as_secs_f64();
+                openmetrics::observe(&metrics::DB_QUERY_SECS, &labels, query_secs, trace_id);
+                Ok(result.inspect_err(|e| {
+                    metrics::DB_ERRORS
+                        .with_label_values(&[&name[..], operation, error_class(e)])
+                        .inc();
+                }))
+            })
+            .instrument(query_span(operation, &db.name))
+            .await;
+        let result = match result {
+            Ok(result) => result,
+            Err(ExecutorError::Busy) => Err(RunError::Busy),
+            Err(ExecutorError::Lost) => Err(RunError::Lost),
+            Err(ExecutorError::Closed) => Err(RunError::Closed),
+        };
+        match &result {
+            Ok(Err(e)) if is_connection_error(e) => attempt.record(false),
+            Err(RunError::NoConnection(_)) => attempt.record(false),
+            Ok(_) | Err(RunError::Lost) => attempt.record(true),
+            Err(RunError::Busy)
+            | Err(RunError::BreakerOpen)
+            | Err(RunError::DeadlineExceeded)
+            // The outcome is unknown: dropping `attempt` lets the next operation probe.
+            | Err(RunError::Closed) => {}
+        }
+        result
+    }
+
+    /// Aggregate counts for business metrics. These are full scans, so call this rarely.
+    pub async fn business_stats(&self, since: DateTime<Utc>) -> Fallible<BusinessStats> {
+        self.read("business_stats", move |conn| {
+            let posts_created = one(slow_queries::load(conn, || {
+                posts::table.filter(posts::created_at.gt(since)).count()
+            }))?;
+            let posts_deleted = one(slow_queries::load(conn, || {
+                posts::table.filter(posts::deleted_at.gt(since)).count()
+            }))?;
+            let day_ago = Utc::now() - chrono::Duration::days(1);
+            let active_users = one(slow_queries::load(conn, || {
+                posts::table
+                    .filter(posts::created_at.gt(day_ago))
+                    .select(sql::<BigInt>("COUNT(DISTINCT user_id)"))
+            }))?;
+            let follows = one(slow_queries::load(conn, || follows::table.count()))?;
+            let posts_by_content = slow_queries::load(conn, || {
+                posts::table
+                    .filter(posts::deleted_at.is_null())
+                    .group_by(posts::content)
+                    .select((posts::content, sql::<BigInt>("COUNT(*)")))
+            })?;
+            Ok(BusinessStats {
+                posts_created,
+                posts_deleted,
+                active_users,
+                follows,
+                posts_by_content,
+            })
+        })
+        .await
+    }
+}
+
 impl PostgresStore {
-    pub async fn new_post(&self, new_post: NewPost) -> Fallible<Post> {
-        let conn = self.pool.get()?;
-        let post = block(move || {
-            conn.transaction::<_, TfError, _>(|| {
+    /// Everything stored about a user, if they exist.
+    pub async fn export_user(&self, user_id: Uuid) -> Fallible<Option<UserExport>> {
+        self.read("export_user", move |conn| {
+            let user = one(slow_queries::load(conn, || users::table.find(user_id)));
+            let user = match user.optional()? {
+                Some(user) => user,
+                None => return Ok(None),
+            };
+            let posts = slow_queries::load(conn, || {
+                posts::table
+                    .filter(posts::user_id.eq(user_id))
+                    .order_by(posts::created_at)
+            })?;
+            let follows = slow_queries::load(conn, || {
+                follows::table
+                    .filter(follows::reads.eq(user_id))
+                    .select(follows::posts)
+            })?;
+            let followers = slow_queries::load(conn, || {
+                follows::table
+                    .filter(follows::posts.eq(user_id))
+                    .select(follows::reads)
+            })?;
+            Ok(Some(UserExport {
+                user,
+                posts,
+                follows,
+                followers,
+            }))
+        })
+        .await
+    }
+
+    /// Permanently delete posts and users which were deleted before `before`. A purged user's
+    /// posts and follows are purged too.
+    pub async fn purge_deleted(&self, before: DateTime<Utc>) -> Fallible<Purged> {
+        self.run("purge_deleted", move |conn| {
+            conn.transaction(|| {
+                let purged_users = users::table
+                    .filter(users::deleted_at.lt(before))
+                    .select(users::id);
+                let follows = slow_queries::write(
+                    diesel::delete(
+                        follows::table.filter(
+                            follows::posts
+                                .eq_any(purged_users)
+                                .or(follows::reads.eq_any(purged_users)),
+                        ),
+                    ),
+                    |q| q.execute(conn),
+                )?;
+                let posts = slow_queries::write(
+                    diesel::delete(
+                        posts::table.filter(
+                            posts::deleted_at
+                                .lt(before)
+                                .or(posts::user_id.eq_any(purged_users)),
+                        ),
+                    ),
+                    |q| q.execute(conn),
+                )?;
+                let users = slow_queries::write(
+                    diesel::delete(users::table.filter(users::deleted_at.lt(before))),
+                    |q| q.execute(conn),
+                )?;
+                Ok(Purged {
+                    posts,
+                    users,
+                    follows,
+                })
+            })
+        })
+        .await
+    }
+
+    /// Insert everything in `snapshot` as it is, IDs and all, in one transaction. Rows are
+    /// inserted in large batches, since Diesel can't `COPY`. The batches' SQL has too many
+    /// parameters to be worth logging, so it isn't recorded by the slow query log.
+    pub async fn bulk_insert(&self, snapshot: Snapshot) -> Fallible<()> {
+        self.run("bulk_insert", move |conn| {
+            conn.transaction(|| {
+                // Postgres allows at most 65535 parameters in a statement, and posts have 6.
+                for users in snapshot.users.chunks(BULK_INSERT_ROWS) {
+                    diesel::insert_into(users::table)
+                        .values(users)
+                        .execute(conn)?;
+                }
+                for posts in snapshot.posts.chunks(BULK_INSERT_ROWS) {
+                    diesel::insert_into(posts::table)
+                        .values(posts)
+                        .execute(conn)?;
+                }
+                for follows in snapshot.follows.chunks(BULK_INSERT_ROWS) {
+                    diesel::insert_into(follows::table)
+                        .values(follows)
+                        .execute(conn)?;
+                }
+                Ok(())
+            })
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl Datastore for PostgresStore {
+    async fn new_post(&self, new_post: NewPost) -> Fallible<Post> {
+        self.run("new_post", move |conn| {
+            conn.transaction(|| {
                 // Insert the new post
-                let post: Post = diesel::insert_into(posts::table)
-                    .values(&new_post)
-                    .get_result(&conn)?;
+                let post: Post = slow_queries::write(
+                    diesel::insert_into(posts::table)
+                        .values(&new_post)
+                        .returning(posts::all_columns),
+                    |q| q.get_result(conn),
+                )?;
 
                 Ok(post)
             })
         })
         .await
-        .to_resp()?;
-        Ok(post)
     }
 
-    pub async fn list_posts(&self, filters: PostFilters) -> Fallible<Vec<Post>> {
-        let conn = self.pool.get()?;
-        let query_result: DbPoolResult<_> = block(move || {
+    async fn list_posts(&self, filters: PostFilters) -> Fallible<Vec<Post>> {
+        self.read("list_posts", move |conn| {
             // Get posts
-            let mut query = posts::table.into_boxed();
-            let limit = filters.limit;
-            for filter in filters.as_sql_where() {
-                query = query.filter(filter);
-            }
-            let posts = query
-                .limit(limit as i64)
-                .order_by(posts::created_at)
-                .get_results(&conn)?;
-
-            Ok(posts)
+            slow_queries::load(conn, || {
+                posts::table
+                    .filter(filters.to_filter().to_sql())
+                    .limit(filters.limit as i64)
+                    .order_by(posts::created_at)
+            })
         })
-        .await;
-        Ok(query_result.to_resp()?)
+        .await
     }
 
-    pub async fn find_post(&self, user_id: Uuid, id: Uuid) -> Fallible<Option<Post>> {
-        let conn = self.pool.get()?;
-        let query_result: DbPoolResult<_> = block(move || {
-            let target_post: Option<Post> = posts::table
-                .find(id)
-                .filter(posts::user_id.eq(user_id))
-                .first(&conn)
-                .optional()?;
+    async fn find_post(&self, user_id: Uuid, id: Uuid) -> Fallible<Option<Post>> {
+        self.read("find_post", move |conn| {
+            let target_post: Option<Post> = one(slow_queries::load(conn, || {
+                posts::table
+                    .find(id)
+                    .filter(posts::user_id.eq(user_id))
+                    .limit(1)
+            }))
+            .optional()?;
 
             guard!(let Some(target_post) = target_post else {
                 return Ok(None);
@@ -74,89 +392,116 @@ impl PostgresStore {
 
             Ok(Some(target_post))
         })
-        .await;
-        Ok(query_result.to_resp()?)
+        .await
     }
 
-    pub async fn delete_post(&self, user_id: Uuid, id: Uuid) -> Fallible<Option<Post>> {
-        let conn = self.pool.get()?;
-        let post = block(move || {
-            conn.transaction::<_, anyhow::Error, _>(|| {
+    async fn delete_post(&self, user_id: Uuid, id: Uuid) -> Fallible<Option<Post>> {
+        self.run("delete_post", move |conn| {
+            conn.transaction(|| {
                 // Delete the post
                 let target = posts::table.find(id);
-                let query_result: Option<Post> = diesel::update(target)
+                let update = diesel::update(target)
                     .filter(posts::user_id.eq(user_id))
                     .set(posts::deleted_at.eq(now))
-                    .get_result::<Post>(&conn)
-                    .optional()?;
+                    .returning(posts::all_columns);
+                slow_queries::write(update, |q| q.get_result::<Post>(conn)).optional()
+            })
+        })
+        .await
+    }
 
-                Ok(query_result)
+    async fn timeline(&self, user_id: Uuid, num_posts: u8) -> Fallible<Vec<Post>> {
+        self.read("timeline", move |conn| {
+            let users_they_follow: Vec<User> = slow_queries::load(conn, || {
+                follows::table
+                    .filter(follows::reads.eq(user_id))
+                    .inner_join(users::table.on(users::id.eq(follows::posts)))
+                    .select(users::all_columns)
+            })?;
+            slow_queries::load(conn, || {
+                Post::belonging_to(&users_they_follow)
+                    .limit(num_posts as i64)
+                    .order_by(posts::created_at)
             })
         })
         .await
-        .to_resp()?;
-        Ok(post)
-    }
-
-    pub async fn _timeline(&self, user_id: Uuid, num_posts: u8) -> Fallible<Vec<Post>> {
-        let conn = self.pool.get()?;
-        let query_result: DbPoolResult<_> = block(move || {
-            let users_they_follow: Vec<User> = follows::table
-                .filter(follows::reads.eq(user_id))
-                .inner_join(users::table.on(users::id.eq(follows::posts)))
-                .select(users::all_columns)
-                .get_results(&conn)?;
-            let timeline: Vec<Post> = Post::belonging_to(&users_they_follow)
-                .limit(num_posts as i64)
-                .order_by(posts::created_at)
-                .get_results(&conn)?;
-            Ok(timeline)
+    }
+
+    async fn new_user(&self, new_user: NewUser) -> Fallible<User> {
+        self.run("new_user", move |conn| {
+            let insert = diesel::insert_into(users::table)
+                .values(&new_user)
+                .returning(users::all_columns);
+            slow_queries::write(insert, |q| q.get_result(conn))
         })
-        .await;
-        Ok(query_result.to_resp()?)
+        .await
     }
 
-    pub async fn _get_user(&self, user_id: Uuid) -> Fallible<Option<User>> {
-        let conn = self.pool.get()?;
-        let query_result: DbPoolResult<_> = block(move || {
-            let user: Option<User> = users::table.find(user_id).get_result(&conn).optional()?;
-            Ok(user)
+    async fn get_user(&self, user_id: Uuid) -> Fallible<Option<User>> {
+        self.read("get_user", move |conn| {
+            one(slow_queries::load(conn, || users::table.find(user_id))).optional()
         })
-        .await;
-        Ok(query_result.to_resp()?)
+        .await
     }
-}
 
-impl PostFilters {
-    pub fn as_sql_where(
-        &self,
-    ) -> Vec<Box<dyn BoxableExpression<posts::table, Pg, SqlType = Bool>>> {
-        let mut wheres: Vec<Box<dyn BoxableExpression<posts::table, Pg, SqlType = Bool>>> =
-            Vec::new();
-        if let Some(id) = self.id {
-            wheres.push(Box::new(posts::id.eq(id)))
-        }
-        if let Some(substring) = &self.text_contains {
-            wheres.push(Box::new(posts::text.like(format!("%{}%", substring))))
-        }
-        if let Some(is_deleted) = self.is_deleted {
-            if is_deleted {
-                wheres.push(Box::new(posts::deleted_at.is_not_null()))
-            } else {
-                wheres.push(Box::new(posts::deleted_at.is_null()))
-            }
-        }
-        if let Some(existed_at) = self.existed_at {
-            wheres.push(Box::new(posts::created_at.lt(existed_at)));
-            wheres.push(Box::new(
-                posts::deleted_at
-                    .is_null()
-                    .or(posts::deleted_at.gt(existed_at)),
-            ));
-        }
-        if let Some(user_id) = self.user_id {
-            wheres.push(Box::new(posts::user_id.eq(user_id)))
-        }
-        wheres
+    async fn follow(&self, reader: Uuid, poster: Uuid) -> Fallible<()> {
+        let follow = Follow {
+            posts: poster,
+            reads: reader,
+        };
+        self.run("follow", move |conn| {
+            let insert = diesel::insert_into(follows::table)
+                .values(&follow)
+                .on_conflict_do_nothing();
+            slow_queries::write(insert, |q| q.execute(conn)).map(|_| ())
+        })
+        .await
     }
 }
+
+/// Run `query` in a transaction whose statements are cancelled if they're still running when
+/// `deadline` passes.
+fn with_statement_timeout<T, F>(conn: &PgConnection, deadline: Instant, query: F) -> QueryResult<T>
+where
+    F: FnOnce(&PgConnection) -> QueryResult<T>,
+{
+    conn.transaction(|| {
+        // A timeout of 0 would mean no timeout at all.
+        let timeout_ms = deadline
+            .saturating_duration_since(Instant::now())
+            .as_millis()
+            .max(1);
+        diesel::sql_query(format!("SET LOCAL statement_timeout = {}", timeout_ms)).execute(conn)?;
+        query(conn)
+    })
+}
+
+/// The only row of a query's results, like `get_result`, for queries run with `load`.
+fn one<T>(rows: QueryResult<Vec<T>>) -> QueryResult<T> {
+    rows?.into_iter().next().ok_or(diesel::NotFound)
+}
+
+/// How long to wait before retrying. The wait is random ("full jitter"), so that operations which
+/// failed together don't all retry at once.
+fn backoff(attempt: u32) -> Duration {
+    RETRY_BACKOFF
+        .mul_f64(2f64.powi(attempt as i32 - 1))
+        .mul_f64(rand::random())
+}
+
+fn observe_secs(histogram: &HistogramVec, labels: &[&str], start: Instant, end: Instant) {
+    histogram
+        .with_label_values(labels)
+        .observe((end - start).as_secs_f64());
+}
+
+/// A span covering one datastore operation, for distributed tracing.
+fn query_span(operation: &'static str, pool: &str) -> tracing::Span {
+    info_span!(
+        "db.query",
+        otel.kind = "client",
+        db.system = "postgresql",
+        db.operation = operation,
+        db.pool = pool
+    )
+}
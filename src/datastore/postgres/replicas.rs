@@ -0,0 +1,132 @@
+//! Read replicas, and deciding whether a read can use one.
+//!
+//! Replicas lag behind the primary, so a client which just wrote might not see its write if it
+//! then reads from a replica. Requests can be marked (see `reading_own_writes`) so that their
+//! reads go to the primary instead.
+use super::Db;
+use crate::metrics;
+use prometheus::IntGauge;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// After a replica fails, how long to send its reads elsewhere before trying it again.
+const UNHEALTHY_FOR: Duration = Duration::from_secs(10);
+
+tokio::task_local! {
+    /// Whether the current request must read from the primary.
+    static READ_PRIMARY: bool;
+}
+
+/// Run `request` with all its reads going to the primary, so that it sees its client's recent
+/// writes.
+pub async fn reading_own_writes<F: Future>(request: F) -> F::Output {
+    READ_PRIMARY.scope(true, request).await
+}
+
+/// Whether reads in the current task must go to the primary.
+pub fn must_read_primary() -> bool {
+    READ_PRIMARY.try_with(|primary| *primary).unwrap_or(false)
+}
+
+/// A read replica, which is skipped for a while after it fails.
+#[derive(Clone)]
+pub(super) struct Replica {
+    pub(super) db: Db,
+    unhealthy_until: Arc<Mutex<Option<Instant>>>,
+    healthy: IntGauge,
+}
+
+impl Replica {
+    pub(super) fn new(db: Db) -> Self {
+        let healthy = metrics::DB_REPLICA_HEALTHY.with_label_values(&[&db.name]);
+        healthy.set(1);
+        Self {
+            db,
+            unhealthy_until: Arc::new(Mutex::new(None)),
+            healthy,
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        let mut unhealthy_until = self.unhealthy_until.lock().expect("replica lock poisoned");
+        match *unhealthy_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                *unhealthy_until = None;
+                self.healthy.set(1);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Stop sending reads to this replica for a while.
+    pub(super) fn mark_unhealthy(&self) {
+        *self.unhealthy_until.lock().expect("replica lock poisoned") =
+            Some(Instant::now() + UNHEALTHY_FOR);
+        self.healthy.set(0);
+    }
+}
+
+/// Every replica, which take turns serving reads.
+#[derive(Clone, Default)]
+pub(super) struct Replicas {
+    pub(super) all: Vec<Replica>,
+    next: Arc<AtomicUsize>,
+}
+
+impl Replicas {
+    pub(super) fn new(all: Vec<Replica>) -> Self {
+        Self {
+            all,
+            next: Arc::default(),
+        }
+    }
+
+    /// The next healthy replica, if there is one.
+    pub(super) fn pick(&self) -> Option<&Replica> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.all.len())
+            .map(|i| &self.all[(start + i) % self.all.len()])
+            .find(|replica| replica.is_healthy())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::{
+        pg::PgConnection,
+        r2d2::{ConnectionManager, Pool},
+    };
+
+    #[actix_rt::test]
+    async fn test_pick_skips_unhealthy() {
+        let replica = |name: &str| {
+            let manager = ConnectionManager::<PgConnection>::new("postgres://127.0.0.1:1/replica");
+            let pool = Pool::builder().min_idle(Some(0)).build_unchecked(manager);
+            Replica::new(Db::new(name.to_owned(), pool).unwrap())
+        };
+        let replicas = Replicas::new(vec![replica("test_replica0"), replica("test_replica1")]);
+        let picked: Vec<_> = (0..4).map(|_| &replicas.pick().unwrap().db.name).collect();
+        assert_eq!(
+            picked
+                .iter()
+                .filter(|&&name| name == "test_replica0")
+                .count(),
+            2
+        );
+
+        replicas.all[0].mark_unhealthy();
+        for _ in 0..4 {
+            assert_eq!(replicas.pick().unwrap().db.name, "test_replica1");
+        }
+        replicas.all[1].mark_unhealthy();
+        assert!(replicas.pick().is_none());
+
+        assert!(!must_read_primary());
+        assert!(reading_own_writes(async { must_read_primary() }).await);
+    }
+}
@@ -0,0 +1,557 @@
+//! Checks that the database's schema matches tables.rs, so that drift (e.g. a migration which
+//! wasn't applied, or a column changed by hand) is found at startup, not by a failing query later.
+//!
+//! What tables.rs expects is read from the `table!` types themselves, so it can't drift either.
+use super::Dsn;
+use crate::config::OnSchemaDrift;
+use crate::datastore::{
+    structs::{Content, ContentMapping},
+    tables::{follows, posts, users},
+};
+use anyhow::bail;
+use diesel::{
+    deserialize::FromSql,
+    expression::Expression,
+    pg::{Pg, PgConnection, PgQueryBuilder},
+    query_builder::{QueryBuilder, QueryFragment},
+    sql_types::{Bool, Nullable, Text, Timestamptz, Uuid},
+    Column, Connection, RunQueryDsl, Table,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use tracing::{error, info, warn};
+
+/// A Postgres type's name, as `information_schema.columns.udt_name` has it.
+trait PgType {
+    const NAME: &'static str;
+    const NULLABLE: bool = false;
+}
+
+impl PgType for Uuid {
+    const NAME: &'static str = "uuid";
+}
+
+impl PgType for Text {
+    const NAME: &'static str = "text";
+}
+
+impl PgType for Timestamptz {
+    const NAME: &'static str = "timestamptz";
+}
+
+impl PgType for ContentMapping {
+    const NAME: &'static str = "content";
+}
+
+impl<T: PgType + diesel::sql_types::NotNull> PgType for Nullable<T> {
+    const NAME: &'static str = T::NAME;
+    const NULLABLE: bool = true;
+}
+
+/// A column tables.rs expects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ExpectedColumn {
+    name: &'static str,
+    udt_name: &'static str,
+    nullable: bool,
+}
+
+/// Describes each column in a table's `AllColumns` tuple.
+trait Columns {
+    fn expected() -> Vec<ExpectedColumn>;
+}
+
+macro_rules! impl_columns {
+    ($($column:ident),+) => {
+        impl<$($column),+> Columns for ($($column,)+)
+        where
+            $($column: Column + Expression, $column::SqlType: PgType,)+
+        {
+            fn expected() -> Vec<ExpectedColumn> {
+                vec![$(ExpectedColumn {
+                    name: $column::NAME,
+                    udt_name: <$column::SqlType as PgType>::NAME,
+                    nullable: <$column::SqlType as PgType>::NULLABLE,
+                }),+]
+            }
+        }
+    };
+}
+
+impl_columns!(A, B);
+impl_columns!(A, B, C);
+impl_columns!(A, B, C, D);
+impl_columns!(A, B, C, D, E);
+impl_columns!(A, B, C, D, E, F);
+impl_columns!(A, B, C, D, E, F, G);
+impl_columns!(A, B, C, D, E, F, G, H);
+
+/// A table tables.rs expects.
+#[derive(Debug)]
+struct ExpectedTable {
+    name: String,
+    columns: Vec<ExpectedColumn>,
+    primary_key: Vec<String>,
+}
+
+impl ExpectedTable {
+    fn of<T>(table: T) -> Self
+    where
+        T: Table,
+        T::FromClause: QueryFragment<Pg>,
+        T::AllColumns: Columns,
+        T::PrimaryKey: QueryFragment<Pg>,
+    {
+        // Diesel doesn't expose names at runtime, except as SQL, e.g. `"follows"."posts", ...`
+        let unquote = |sql: &str| sql.trim_matches('"').to_owned();
+        Self {
+            name: unquote(&to_sql(&table.from_clause())),
+            columns: T::AllColumns::expected(),
+            primary_key: to_sql(&table.primary_key())
+                .split(", ")
+                .map(|column| unquote(column.rsplit('.').next().unwrap_or(column)))
+                .collect(),
+        }
+    }
+}
+
+fn to_sql<Q: QueryFragment<Pg>>(query: &Q) -> String {
+    let mut sql = PgQueryBuilder::new();
+    query
+        .to_sql(&mut sql)
+        .expect("tables and columns always have SQL");
+    sql.finish()
+}
+
+fn expected_tables() -> Vec<ExpectedTable> {
+    vec![
+        ExpectedTable::of(posts::table),
+        ExpectedTable::of(users::table),
+        ExpectedTable::of(follows::table),
+    ]
+}
+
+/// A column the database has.
+#[derive(Debug, Clone, QueryableByName)]
+struct ActualColumn {
+    #[sql_type = "Text"]
+    table_name: String,
+    #[sql_type = "Text"]
+    column_name: String,
+    #[sql_type = "Text"]
+    udt_name: String,
+    #[sql_type = "Bool"]
+    nullable: bool,
+    #[sql_type = "Bool"]
+    has_default: bool,
+}
+
+/// A column of a primary key the database has, in the key's order.
+#[derive(Debug, Clone, QueryableByName)]
+struct ActualKeyColumn {
+    #[sql_type = "Text"]
+    table_name: String,
+    #[sql_type = "Text"]
+    column_name: String,
+}
+
+#[derive(Debug, Clone, QueryableByName)]
+struct EnumLabel {
+    #[sql_type = "Text"]
+    type_name: String,
+    #[sql_type = "Text"]
+    label: String,
+}
+
+/// The parts of the database's schema which tables.rs describes.
+#[derive(Debug, Default)]
+struct ActualSchema {
+    columns: Vec<ActualColumn>,
+    primary_keys: Vec<ActualKeyColumn>,
+    /// Labels of each enum type, in order.
+    enums: BTreeMap<String, Vec<String>>,
+}
+
+impl ActualSchema {
+    fn load(conn: &PgConnection, tables: &[ExpectedTable]) -> diesel::QueryResult<Self> {
+        let names: Vec<_> = tables.iter().map(|table| table.name.clone()).collect();
+        let columns = diesel::sql_query(
+            "SELECT table_name::text, column_name::text, udt_name::text,
+                is_nullable = 'YES' AS nullable, column_default IS NOT NULL AS has_default
+            FROM information_schema.columns
+            WHERE table_schema = current_schema() AND table_name = ANY($1)
+            ORDER BY table_name, ordinal_position",
+        )
+        .bind::<diesel::sql_types::Array<Text>, _>(&names)
+        .load(conn)?;
+        let primary_keys = diesel::sql_query(
+            "SELECT tc.table_name::text, kcu.column_name::text
+            FROM information_schema.table_constraints tc
+            JOIN information_schema.key_column_usage kcu
+                USING (constraint_schema, constraint_name, table_name)
+            WHERE tc.table_schema = current_schema() AND tc.constraint_type = 'PRIMARY KEY'
+                AND tc.table_name = ANY($1)
+            ORDER BY tc.table_name, kcu.ordinal_position",
+        )
+        .bind::<diesel::sql_types::Array<Text>, _>(&names)
+        .load(conn)?;
+        let labels: Vec<EnumLabel> = diesel::sql_query(
+            "SELECT t.typname::text AS type_name, e.enumlabel::text AS label
+            FROM pg_type t
+            JOIN pg_enum e ON e.enumtypid = t.oid
+            WHERE t.typnamespace = current_schema()::regnamespace
+            ORDER BY t.typname, e.enumsortorder",
+        )
+        .load(conn)?;
+        let mut enums = BTreeMap::<_, Vec<_>>::new();
+        for label in labels {
+            enums.entry(label.type_name).or_default().push(label.label);
+        }
+        Ok(Self {
+            columns,
+            primary_keys,
+            enums,
+        })
+    }
+}
+
+/// One way the database's schema differs from tables.rs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    MissingTable(String),
+    MissingColumn {
+        table: String,
+        column: String,
+    },
+    ColumnType {
+        table: String,
+        column: String,
+        expected: String,
+        actual: String,
+    },
+    Nullability {
+        table: String,
+        column: String,
+        nullable: bool,
+    },
+    /// A column tables.rs doesn't know about, which inserts can't leave out.
+    RequiredColumn {
+        table: String,
+        column: String,
+    },
+    PrimaryKey {
+        table: String,
+        expected: Vec<String>,
+        actual: Vec<String>,
+    },
+    MissingEnum(String),
+    /// A variant whose label isn't in the database's enum, so it can't be written.
+    MissingEnumVariant {
+        name: String,
+        variant: String,
+    },
+    /// A label the code can't read.
+    UnknownEnumLabel {
+        name: String,
+        label: String,
+    },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nullability = |nullable: bool| if nullable { "NULL" } else { "NOT NULL" };
+        match self {
+            Difference::MissingTable(table) => write!(f, "table {} is missing", table),
+            Difference::MissingColumn { table, column } => {
+                write!(f, "column {}.{} is missing", table, column)
+            }
+            Difference::ColumnType {
+                table,
+                column,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "column {}.{} is {}, but tables.rs expects {}",
+                table, column, actual, expected
+            ),
+            Difference::Nullability {
+                table,
+                column,
+                nullable,
+            } => write!(
+                f,
+                "column {}.{} is {}, but tables.rs expects {}",
+                table,
+                column,
+                nullability(*nullable),
+                nullability(!nullable)
+            ),
+            Difference::RequiredColumn { table, column } => write!(
+                f,
+                "column {}.{} isn't in tables.rs, but is NOT NULL with no default, so inserts fail",
+                table, column
+            ),
+            Difference::PrimaryKey {
+                table,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "table {} has primary key ({}), but tables.rs expects ({})",
+                table,
+                actual.join(", "),
+                expected.join(", ")
+            ),
+            Difference::MissingEnum(name) => write!(f, "enum type {} is missing", name),
+            Difference::MissingEnumVariant { name, variant } => write!(
+                f,
+                "enum type {} has no label for {}, so it can't be written",
+                name, variant
+            ),
+            Difference::UnknownEnumLabel { name, label } => write!(
+                f,
+                "enum type {} has label '{}', which the code can't read",
+                name, label
+            ),
+        }
+    }
+}
+
+/// Every way `actual` differs from the expected tables.
+fn diff_tables(expected: &[ExpectedTable], actual: &ActualSchema) -> Vec<Difference> {
+    let mut diffs = Vec::new();
+    for table in expected {
+        let columns: Vec<_> = actual
+            .columns
+            .iter()
+            .filter(|column| column.table_name == table.name)
+            .collect();
+        if columns.is_empty() {
+            diffs.push(Difference::MissingTable(table.name.clone()));
+            continue;
+        }
+        for expected in &table.columns {
+            let column = columns.iter().find(|c| c.column_name == expected.name);
+            let (table, name) = (table.name.clone(), expected.name.to_owned());
+            match column {
+                None => diffs.push(Difference::MissingColumn {
+                    table,
+                    column: name,
+                }),
+                Some(column) if column.udt_name != expected.udt_name => {
+                    diffs.push(Difference::ColumnType {
+                        table,
+                        column: name,
+                        expected: expected.udt_name.to_owned(),
+                        actual: column.udt_name.clone(),
+                    })
+                }
+                Some(column) if column.nullable != expected.nullable => {
+                    diffs.push(Difference::Nullability {
+                        table,
+                        column: name,
+                        nullable: column.nullable,
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        // Other columns are fine, e.g. ones added by a migration before the code uses them,
+        // unless inserts must set them.
+        for column in columns {
+            let known = table.columns.iter().any(|c| c.name == column.column_name);
+            if !known && !column.nullable && !column.has_default {
+                diffs.push(Difference::RequiredColumn {
+                    table: table.name.clone(),
+                    column: column.column_name.clone(),
+                });
+            }
+        }
+        let primary_key: Vec<_> = actual
+            .primary_keys
+            .iter()
+            .filter(|key| key.table_name == table.name)
+            .map(|key| key.column_name.clone())
+            .collect();
+        if primary_key != table.primary_key {
+            diffs.push(Difference::PrimaryKey {
+                table: table.name.clone(),
+                expected: table.primary_key.clone(),
+                actual: primary_key,
+            });
+        }
+    }
+    diffs
+}
+
+/// Every way the database's enum type `name` differs from `variants`. Labels are checked by
+/// decoding them, the same way rows are.
+fn diff_enum<T, ST>(name: &str, variants: &[T], actual: &ActualSchema) -> Vec<Difference>
+where
+    T: FromSql<ST, Pg> + PartialEq + fmt::Debug,
+{
+    let labels = match actual.enums.get(name) {
+        Some(labels) => labels,
+        None => return vec![Difference::MissingEnum(name.to_owned())],
+    };
+    let mut decoded = Vec::new();
+    let mut diffs = Vec::new();
+    for label in labels {
+        match T::from_sql(Some(label.as_bytes())) {
+            Ok(variant) => decoded.push(variant),
+            Err(_) => diffs.push(Difference::UnknownEnumLabel {
+                name: name.to_owned(),
+                label: label.clone(),
+            }),
+        }
+    }
+    for variant in variants {
+        if !decoded.contains(variant) {
+            diffs.push(Difference::MissingEnumVariant {
+                name: name.to_owned(),
+                variant: format!("{:?}", variant),
+            });
+        }
+    }
+    diffs
+}
+
+/// Every way the database's schema differs from tables.rs.
+pub fn diff(dsn: Dsn) -> Result<Vec<Difference>, anyhow::Error> {
+    let conn = PgConnection::establish(&String::from(dsn))?;
+    let tables = expected_tables();
+    let actual = ActualSchema::load(&conn, &tables)?;
+    let mut diffs = diff_tables(&tables, &actual);
+    diffs.extend(diff_enum::<_, ContentMapping>(
+        ContentMapping::NAME,
+        Content::ALL,
+        &actual,
+    ));
+    Ok(diffs)
+}
+
+/// Check the database's schema at startup, logging each difference. With `OnSchemaDrift::Fail`,
+/// differences are an error.
+pub fn check(dsn: Dsn, on_drift: OnSchemaDrift) -> Result<(), anyhow::Error> {
+    if on_drift == OnSchemaDrift::Ignore {
+        return Ok(());
+    }
+    let diffs = diff(dsn)?;
+    if diffs.is_empty() {
+        info!("the database's schema matches tables.rs");
+        return Ok(());
+    }
+    for difference in &diffs {
+        match on_drift {
+            OnSchemaDrift::Fail => error!(%difference, "the database's schema has drifted"),
+            _ => warn!(%difference, "the database's schema has drifted"),
+        }
+    }
+    if on_drift == OnSchemaDrift::Fail {
+        bail!(
+            "the database's schema differs from tables.rs in {} ways",
+            diffs.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(table: &str, name: &str, udt_name: &str, nullable: bool) -> ActualColumn {
+        ActualColumn {
+            table_name: table.to_owned(),
+            column_name: name.to_owned(),
+            udt_name: udt_name.to_owned(),
+            nullable,
+            has_default: false,
+        }
+    }
+
+    fn key(table: &str, column: &str) -> ActualKeyColumn {
+        ActualKeyColumn {
+            table_name: table.to_owned(),
+            column_name: column.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_expected_tables() {
+        let tables = expected_tables();
+        let follows = &tables[2];
+        assert_eq!(follows.name, "follows");
+        assert_eq!(follows.primary_key, vec!["posts", "reads"]);
+        let posts = &tables[0];
+        assert_eq!(posts.primary_key, vec!["id"]);
+        assert_eq!(
+            posts.columns[2],
+            ExpectedColumn {
+                name: "deleted_at",
+                udt_name: "timestamptz",
+                nullable: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let tables = expected_tables();
+        // The schema before the migrations were fixed
+        let mut actual = ActualSchema {
+            columns: vec![
+                column("posts", "id", "uuid", false),
+                column("posts", "created_at", "timestamptz", false),
+                column("posts", "deleted_at", "timestamptz", true),
+                column("posts", "text", "text", true),
+                column("posts", "user_id", "uuid", false),
+                column("posts", "extra", "int4", true),
+                column("follows", "posts", "uuid", false),
+                column("follows", "reads", "text", false),
+                column("follows", "required", "text", false),
+            ],
+            primary_keys: vec![key("posts", "id")],
+            enums: BTreeMap::new(),
+        };
+        let diffs: Vec<_> = diff_tables(&tables, &actual)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            diffs,
+            vec![
+                "column posts.content is missing",
+                "column posts.text is NULL, but tables.rs expects NOT NULL",
+                "table users is missing",
+                "column follows.reads is text, but tables.rs expects uuid",
+                "column follows.required isn't in tables.rs, but is NOT NULL with no default, so \
+                 inserts fail",
+                "table follows has primary key (), but tables.rs expects (posts, reads)",
+            ]
+        );
+
+        let content = |actual: &ActualSchema| {
+            diff_enum::<_, ContentMapping>("content", Content::ALL, actual)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(content(&actual), vec!["enum type content is missing"]);
+        actual
+            .enums
+            .insert("content".to_owned(), vec!["image".to_owned()]);
+        assert_eq!(
+            content(&actual),
+            vec![
+                "enum type content has label 'image', which the code can't read",
+                "enum type content has no label for None, so it can't be written",
+            ]
+        );
+        actual
+            .enums
+            .insert("content".to_owned(), vec!["none".to_owned()]);
+        assert!(content(&actual).is_empty());
+    }
+}
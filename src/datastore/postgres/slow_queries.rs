@@ -0,0 +1,332 @@
+//! The slow query log. Datastore operations whose queries take at least `slow_query_threshold_ms`
+//! are logged, with the SQL of each statement which went through `load` (reads) or `write`. Bind
+//! parameters hold user data, so they're never logged. A sample of slow reads is run again with
+//! `EXPLAIN (ANALYZE, BUFFERS)` and the plans written to a file, to show why they were slow.
+use crate::metrics;
+use chrono::Utc;
+use diesel::{
+    pg::{Pg, PgConnection, PgQueryBuilder},
+    query_builder::{AstPass, Query, QueryBuilder, QueryFragment, QueryId},
+    query_dsl::{LoadQuery, RunQueryDsl},
+    sql_types::Text,
+    Connection, QueryResult,
+};
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// When the plan file grows past this, it's moved aside and a new one started.
+const MAX_PLAN_FILE_BYTES: u64 = 10 * 1024 * 1024;
+
+thread_local! {
+    /// The operation running on this thread, if slow queries are being logged.
+    static CURRENT: RefCell<Option<Operation>> = const { RefCell::new(None) };
+}
+
+/// Which operations are slow, and where to write their plans.
+pub struct SlowQueryLog {
+    threshold: Duration,
+    plans: Option<Plans>,
+}
+
+impl SlowQueryLog {
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            plans: None,
+        }
+    }
+
+    /// Also explain `sample_rate` (0 to 1) of slow statements, writing their plans to `path`.
+    pub fn with_plans(mut self, path: PathBuf, sample_rate: f64) -> io::Result<Self> {
+        self.plans = Some(Plans {
+            sample_rate,
+            file: Mutex::new(RotatingFile::open(path, MAX_PLAN_FILE_BYTES)?),
+        });
+        Ok(self)
+    }
+}
+
+struct Plans {
+    sample_rate: f64,
+    file: Mutex<RotatingFile>,
+}
+
+/// A datastore operation, and the statements it's run so far.
+struct Operation {
+    log: Arc<SlowQueryLog>,
+    name: &'static str,
+    pool: String,
+    statements: Vec<Statement>,
+}
+
+struct Statement {
+    sql: String,
+    took: Duration,
+}
+
+/// Run the queries of datastore operation `name`, on this thread, and log them if they're slow.
+pub(super) fn logging<T, F>(log: &Arc<SlowQueryLog>, name: &'static str, pool: &str, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let operation = Operation {
+        log: Arc::clone(log),
+        name,
+        pool: pool.to_owned(),
+        statements: Vec::new(),
+    };
+    CURRENT.with(|current| *current.borrow_mut() = Some(operation));
+    let started = Instant::now();
+    let result = f();
+    let took = started.elapsed();
+    let operation = CURRENT.with(|current| current.borrow_mut().take());
+    if let Some(operation) = operation.filter(|_| took >= log.threshold) {
+        metrics::DB_SLOW_QUERIES
+            .with_label_values(&[&operation.pool, name])
+            .inc();
+        let statements: Vec<_> = operation
+            .statements
+            .iter()
+            .map(|statement| format!("{} -- {}ms", statement.sql, statement.took.as_millis()))
+            .collect();
+        let duration_ms = took.as_millis() as u64;
+        let db = &operation.pool[..];
+        // Statements which didn't go through `load` or `write` (e.g. bulk inserts) aren't captured
+        if statements.is_empty() {
+            warn!(operation = name, db, duration_ms, "slow DB query");
+        } else {
+            let sql = &statements.join("; ")[..];
+            warn!(operation = name, db, duration_ms, sql, "slow DB query");
+        }
+    }
+    result
+}
+
+/// Load the results of the query `build` makes. If slow queries are being logged, its SQL is
+/// recorded, and if it's slow it might be explained, which means building and running it again.
+/// ANALYZE really runs the query, so this must only be used for reads.
+pub(super) fn load<Q, U, B>(conn: &PgConnection, build: B) -> QueryResult<Vec<U>>
+where
+    B: Fn() -> Q,
+    Q: LoadQuery<PgConnection, U> + QueryFragment<Pg>,
+{
+    let log = match current_log() {
+        Some(log) => log,
+        None => return build().load(conn),
+    };
+    let query = build();
+    let sql = redacted_sql(&query);
+    let started = Instant::now();
+    let result = query.load(conn);
+    let took = started.elapsed();
+    if let (Ok(_), Some(plans)) = (&result, &log.plans) {
+        if took >= log.threshold && rand::random::<f64>() < plans.sample_rate {
+            plans.explain(conn, &sql, took, build());
+        }
+    }
+    record(Statement { sql, took });
+    result
+}
+
+/// Run `query` with `run`, e.g. `|q| q.execute(conn)`. If slow queries are being logged, its SQL
+/// is recorded. Unlike `load`, it's never explained, since that would run it again.
+pub(super) fn write<Q, T, F>(query: Q, run: F) -> QueryResult<T>
+where
+    Q: QueryFragment<Pg>,
+    F: FnOnce(Q) -> QueryResult<T>,
+{
+    if current_log().is_none() {
+        return run(query);
+    }
+    let sql = redacted_sql(&query);
+    let started = Instant::now();
+    let result = run(query);
+    record(Statement {
+        sql,
+        took: started.elapsed(),
+    });
+    result
+}
+
+/// The slow query log of the operation running on this thread, if any.
+fn current_log() -> Option<Arc<SlowQueryLog>> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|op| Arc::clone(&op.log)))
+}
+
+fn record(statement: Statement) {
+    CURRENT.with(|current| {
+        if let Some(operation) = current.borrow_mut().as_mut() {
+            operation.statements.push(statement);
+        }
+    });
+}
+
+/// The query's SQL, with `$1`, `$2`... in place of its bind parameters.
+fn redacted_sql<Q: QueryFragment<Pg>>(query: &Q) -> String {
+    let mut sql = PgQueryBuilder::new();
+    match query.to_sql(&mut sql) {
+        Ok(()) => sql.finish(),
+        Err(e) => format!("<couldn't build SQL: {}>", e),
+    }
+}
+
+impl Plans {
+    /// Explain a slow statement and write its plan. Failures are logged, but don't fail the
+    /// operation.
+    fn explain<Q: QueryFragment<Pg>>(&self, conn: &PgConnection, sql: &str, took: Duration, q: Q) {
+        // Explain in a (nested) transaction, so that if it fails, e.g. by running past the
+        // statement timeout, the operation's own transaction can still commit.
+        let plan = match conn.transaction(|| Explain(q).load::<String>(conn)) {
+            Ok(plan) => plan,
+            Err(e) => {
+                warn!(error = %e, "couldn't explain slow DB query");
+                return;
+            }
+        };
+        let (name, pool) = CURRENT.with(|current| match current.borrow().as_ref() {
+            Some(op) => (op.name, op.pool.clone()),
+            None => ("unknown", "unknown".to_owned()),
+        });
+        let entry = format!(
+            "-- {} {} on {} took {}ms\n{}\n{}\n\n",
+            Utc::now().to_rfc3339(),
+            name,
+            pool,
+            took.as_millis(),
+            sql,
+            plan.join("\n")
+        );
+        let mut file = self.file.lock().expect("plan file lock poisoned");
+        if let Err(e) = file.write(&entry) {
+            warn!(error = %e, path = %file.path.display(), "couldn't write slow DB query plan");
+        }
+    }
+}
+
+/// `EXPLAIN (ANALYZE, BUFFERS)` of a query. Each row is one line of the plan.
+struct Explain<Q>(Q);
+
+impl<Q> QueryId for Explain<Q> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<Q> Query for Explain<Q> {
+    type SqlType = Text;
+}
+
+impl<Q: QueryFragment<Pg>> QueryFragment<Pg> for Explain<Q> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.push_sql("EXPLAIN (ANALYZE, BUFFERS) ");
+        self.0.walk_ast(out.reborrow())
+    }
+}
+
+impl<Q> RunQueryDsl<PgConnection> for Explain<Q> {}
+
+/// An append-only file which, once it's bigger than `max_bytes`, is moved to `<path>.1` (replacing
+/// the previous one) and started again.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_bytes: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64) -> io::Result<Self> {
+        let file = open_append(&path)?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            len,
+            max_bytes,
+        })
+    }
+
+    fn write(&mut self, entry: &str) -> io::Result<()> {
+        if self.len > 0 && self.len + entry.len() as u64 > self.max_bytes {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&self.path, rotated)?;
+            self.file = open_append(&self.path)?;
+            self.len = 0;
+        }
+        self.file.write_all(entry.as_bytes())?;
+        self.len += entry.len() as u64;
+        Ok(())
+    }
+}
+
+/// Plans show parameters' values (e.g. in filters), so only the owner can read the file.
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::tables::posts;
+    use diesel::{debug_query, ExpressionMethods, QueryDsl};
+
+    #[test]
+    fn test_sql_is_redacted() {
+        let user_id = uuid::Uuid::new_v4();
+        let query = posts::table.filter(posts::user_id.eq(user_id)).limit(10);
+        let sql = redacted_sql(&query);
+        assert!(sql.starts_with("SELECT "), "{}", sql);
+        assert!(sql.contains("$1"), "{}", sql);
+        assert!(!sql.contains(&user_id.to_string()), "{}", sql);
+
+        let explain = debug_query::<Pg, _>(&Explain(query)).to_string();
+        assert!(explain.starts_with("EXPLAIN (ANALYZE, BUFFERS) SELECT "));
+    }
+
+    #[test]
+    fn test_writes_are_recorded() {
+        let user_id = uuid::Uuid::new_v4();
+        let delete = || diesel::delete(posts::table.filter(posts::user_id.eq(user_id)));
+        // Nothing is recorded unless an operation is being logged
+        assert_eq!(write(delete(), |_| Ok(1)), Ok(1));
+        let operation = Operation {
+            log: Arc::new(SlowQueryLog::new(Duration::from_secs(1))),
+            name: "test",
+            pool: "primary".to_owned(),
+            statements: Vec::new(),
+        };
+        CURRENT.with(|current| *current.borrow_mut() = Some(operation));
+        assert_eq!(write(delete(), |_| Ok(2)), Ok(2));
+        let operation = CURRENT.with(|current| current.borrow_mut().take()).unwrap();
+        let sql: Vec<_> = operation.statements.iter().map(|s| &s.sql[..]).collect();
+        assert_eq!(
+            sql,
+            vec![r#"DELETE FROM "posts" WHERE "posts"."user_id" = $1"#]
+        );
+    }
+
+    #[test]
+    fn test_plan_file_rotates() {
+        let path = std::env::temp_dir().join(format!("{}-plans.log", uuid::Uuid::new_v4()));
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+        let mut file = RotatingFile::open(path.clone(), 10).unwrap();
+        file.write("first\n").unwrap();
+        file.write("second\n").unwrap();
+        file.write("third\n").unwrap();
+        assert_eq!(fs::read_to_string(&rotated).unwrap(), "second\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third\n");
+        fs::remove_file(path).unwrap();
+        fs::remove_file(rotated).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use crate::datastore::tables::users;
+use crate::datastore::tables::{follows, users};
 use crate::datastore::{postfilters::PostFilters, tables::posts};
 use chrono::{offset::Utc, DateTime};
 use diesel_derive_enum::DbEnum;
@@ -6,7 +6,10 @@ use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 /// A user of the website.
-#[derive(Queryable, Identifiable, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(
+    Queryable, Identifiable, Insertable, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash,
+)]
+#[table_name = "users"]
 pub struct User {
     pub id: Uuid,
     pub created_at: DateTime<Utc>,
@@ -30,9 +33,20 @@ pub struct NewUser {
 
 /// A post from a user
 #[derive(
-    Queryable, Identifiable, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, Associations,
+    Queryable,
+    Identifiable,
+    Insertable,
+    Serialize,
+    Deserialize,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    Associations,
 )]
 #[belongs_to(User)]
+#[table_name = "posts"]
 pub struct Post {
     pub id: Uuid,
     pub created_at: DateTime<Utc>,
@@ -47,50 +61,20 @@ pub enum Content {
     None,
 }
 
+impl Content {
+    /// Every variant, so the database's `content` type can be checked for them. Keep it in sync.
+    pub const ALL: &'static [Content] = &[Content::None];
+}
+
 impl Post {
     /// Has this post been deleted?
     pub fn is_deleted(&self) -> bool {
         self.deleted_at.is_some()
     }
 
-    #[allow(dead_code, clippy::nonminimal_bool)]
     /// Does this post match all specified filters?
     pub fn matches(&self, filters: &PostFilters) -> bool {
-        if let Some(user_id) = filters.user_id {
-            if user_id != self.user_id {
-                return false;
-            }
-        }
-        if let Some(user_id) = &filters.user_id {
-            if user_id != &self.user_id {
-                return false;
-            }
-        }
-        if let Some(uuid) = &filters.id {
-            if uuid != &self.id {
-                return false;
-            }
-        }
-        if let Some(is_deleted) = filters.is_deleted {
-            if is_deleted != self.is_deleted() {
-                return false;
-            }
-        }
-        if let Some(substring) = &filters.text_contains {
-            if !self.text.contains(substring) {
-                return false;
-            }
-        }
-        if let Some(existed_at) = filters.existed_at {
-            if let Some(deleted_at) = self.deleted_at {
-                if !(self.created_at < existed_at && existed_at < deleted_at) {
-                    return false;
-                }
-            } else if !(self.created_at < existed_at) {
-                return false;
-            }
-        }
-        true
+        filters.to_filter().matches(self)
     }
 }
 
@@ -103,6 +87,50 @@ pub struct NewPost {
     pub user_id: Uuid,
 }
 
+/// User `reads` follows the posts of user `posts`.
+#[derive(
+    Insertable, Queryable, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash,
+)]
+#[table_name = "follows"]
+pub struct Follow {
+    pub posts: Uuid,
+    pub reads: Uuid,
+}
+
+/// Everything stored about a user.
+#[derive(Debug, Clone, Serialize)]
+pub struct UserExport {
+    pub user: User,
+    /// Including deleted posts
+    pub posts: Vec<Post>,
+    /// Users whose posts they read
+    pub follows: Vec<Uuid>,
+    /// Users who read their posts
+    pub followers: Vec<Uuid>,
+}
+
+/// How many rows were permanently deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Purged {
+    pub posts: usize,
+    pub users: usize,
+    pub follows: usize,
+}
+
+/// Aggregate counts, for business metrics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusinessStats {
+    /// Posts created since the start of the period
+    pub posts_created: i64,
+    /// Posts deleted since the start of the period
+    pub posts_deleted: i64,
+    /// Users who created a post in the last day
+    pub active_users: i64,
+    pub follows: i64,
+    /// How many posts (which haven't been deleted) have each type of content
+    pub posts_by_content: Vec<(Content, i64)>,
+}
+
 #[cfg(test)]
 mod post_tests {
     use super::*;
@@ -121,6 +149,8 @@ mod post_tests {
             created_at: Utc::now(),
             deleted_at: None,
         };
+        // Timestamps are compared to the microsecond, like in Postgres
+        sleep(std::time::Duration::from_micros(10));
 
         assert!(active_post.matches(&PostFilters {
             user_id: Some(user_id),
@@ -153,4 +183,43 @@ mod post_tests {
             ..Default::default()
         }));
     }
+
+    #[test]
+    fn test_range_and_set_filters() {
+        let created_at = Utc::now();
+        let post = Post {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            text: String::new(),
+            content: Content::None,
+            created_at,
+            deleted_at: Some(created_at + chrono::Duration::hours(1)),
+        };
+        let minute = chrono::Duration::minutes(1);
+        let matches = |filters: PostFilters| post.matches(&filters);
+
+        assert!(matches(PostFilters {
+            created_after: Some(created_at - minute),
+            created_before: Some(created_at + minute),
+            deleted_after: Some(created_at + minute),
+            ..Default::default()
+        }));
+        assert!(!matches(PostFilters {
+            created_after: Some(created_at + minute),
+            ..Default::default()
+        }));
+        assert!(!matches(PostFilters {
+            deleted_before: Some(created_at + minute),
+            ..Default::default()
+        }));
+        assert!(matches(PostFilters {
+            ids: Some(vec![Uuid::new_v4(), post.id]),
+            user_ids: Some(vec![post.user_id]),
+            ..Default::default()
+        }));
+        assert!(!matches(PostFilters {
+            user_ids: Some(vec![Uuid::new_v4()]),
+            ..Default::default()
+        }));
+    }
 }
@@ -0,0 +1,555 @@
+//! Synthetic data for load tests and demos. Generation is seeded, so the same spec always makes the
+//! same users, posts and follows, down to their IDs and timestamps.
+//!
+//! The shapes are meant to look like a real social network: a few users have most of the
+//! followers, most posts are short but some are long, and some posts have tags, mention other
+//! users, or have been deleted.
+use crate::datastore::{
+    memory::Snapshot,
+    structs::{Content, Follow, NewPost, NewUser, Post, User},
+    Datastore,
+};
+use crate::twoface::Fallible;
+use anyhow::{bail, Context};
+use chrono::{offset::Utc, DateTime, Duration};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use rand::{distributions::WeightedIndex, seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use uuid::{Builder, Uuid, Variant, Version};
+
+/// Words which post text is made of.
+const WORDS: &[&str] = &[
+    "the", "a", "and", "of", "to", "in", "is", "it", "that", "for", "on", "was", "with", "just",
+    "really", "today", "new", "good", "people", "time", "think", "know", "about", "what", "this",
+    "my", "your", "coffee", "train", "weather", "book", "music", "game", "code", "release", "bug",
+    "weekend", "morning", "tonight", "finally", "again", "never", "always", "maybe", "love",
+    "hate", "read", "watch", "build", "ship", "fix", "learn", "city", "park", "dog", "cat",
+];
+
+/// Tags, most popular first. Earlier tags are used much more often than later ones.
+const TAGS: &[&str] = &[
+    "news",
+    "rust",
+    "music",
+    "photography",
+    "food",
+    "travel",
+    "books",
+    "gaming",
+    "sports",
+    "art",
+    "science",
+    "movies",
+    "fitness",
+    "coffee",
+    "cats",
+    "dogs",
+    "weekend",
+    "programming",
+    "design",
+    "history",
+];
+
+/// Name stems for generated users. Each user's name also has its index, so names are unique.
+const NAMES: &[&str] = &[
+    "ada",
+    "alan",
+    "barbara",
+    "claude",
+    "donald",
+    "edsger",
+    "frances",
+    "grace",
+    "hedy",
+    "ivan",
+    "john",
+    "ken",
+    "leslie",
+    "margaret",
+    "niklaus",
+    "radia",
+    "shafi",
+    "tim",
+    "whitfield",
+    "yukihiro",
+];
+
+/// Median words in a post. Lengths are log-normally distributed around it.
+const MEDIAN_WORDS: f64 = 12.0;
+/// Spread of post lengths. About 1 in 20 posts is over 4 times the median.
+const WORDS_SIGMA: f64 = 0.8;
+const MAX_WORDS: usize = 120;
+
+/// What to generate, and where to write it. Read from a TOML file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Spec {
+    /// The same seed (and spec) always generates the same data.
+    pub seed: u64,
+    pub users: usize,
+    /// Mean posts per user. Counts are exponentially distributed, so most users post a little
+    /// and a few post a lot.
+    #[serde(default = "posts_per_user")]
+    pub posts_per_user: f64,
+    /// Mean users each user follows.
+    #[serde(default = "follows_per_user")]
+    pub follows_per_user: f64,
+    /// Exponent of the power law which users' follower counts follow. Must be over 2, so the
+    /// counts have a mean. Closer to 2, the most followed users have more of the followers.
+    #[serde(default = "follower_exponent")]
+    pub follower_exponent: f64,
+    /// Fraction of posts (0 to 1) with tags.
+    #[serde(default = "tag_rate")]
+    pub tag_rate: f64,
+    /// Fraction of posts (0 to 1) which mention another user.
+    #[serde(default = "mention_rate")]
+    pub mention_rate: f64,
+    /// Fraction of posts (0 to 1) which have been deleted.
+    #[serde(default = "deleted_rate")]
+    pub deleted_rate: f64,
+    /// When the first user could have joined. Everything happens in the `days` after it.
+    #[serde(default = "start")]
+    pub start: DateTime<Utc>,
+    #[serde(default = "days")]
+    pub days: u32,
+    pub output: Output,
+    /// JSON file to list the generated IDs in, for load test scripts.
+    #[serde(default)]
+    pub manifest_file: Option<PathBuf>,
+}
+
+/// Where generated data goes.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "to")]
+pub enum Output {
+    /// Through the datastore's operations, like the API would. Postgres assigns IDs and
+    /// timestamps, so only the shape of the data is reproducible.
+    Store,
+    /// Straight into Postgres's tables in large batches, in one transaction. Much faster than
+    /// `store`, and keeps the generated IDs and timestamps.
+    Bulk,
+    /// A fixtures file for the `memory` datastore.
+    Fixtures { file: PathBuf },
+}
+
+fn posts_per_user() -> f64 {
+    20.0
+}
+
+fn follows_per_user() -> f64 {
+    10.0
+}
+
+fn follower_exponent() -> f64 {
+    2.1
+}
+
+fn tag_rate() -> f64 {
+    0.3
+}
+
+fn mention_rate() -> f64 {
+    0.15
+}
+
+fn deleted_rate() -> f64 {
+    0.05
+}
+
+fn start() -> DateTime<Utc> {
+    "2020-01-01T00:00:00Z".parse().expect("valid timestamp")
+}
+
+fn days() -> u32 {
+    365
+}
+
+impl Spec {
+    /// Read and check a spec file.
+    pub fn read(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("couldn't read {}", path.display()))?;
+        let spec: Self = toml::from_str(&contents)
+            .with_context(|| format!("couldn't parse {}", path.display()))?;
+        spec.check()?;
+        Ok(spec)
+    }
+
+    fn check(&self) -> Result<(), anyhow::Error> {
+        if self.users < 2 {
+            bail!("users must be at least 2");
+        }
+        if self.days == 0 {
+            bail!("days must be greater than 0");
+        }
+        if ![self.posts_per_user, self.follows_per_user]
+            .iter()
+            .all(|mean| (0.0..).contains(mean))
+        {
+            bail!("posts_per_user and follows_per_user must not be negative");
+        }
+        // Written so that NaN fails too.
+        if self.follower_exponent.is_nan() || self.follower_exponent <= 2.0 {
+            bail!("follower_exponent must be greater than 2");
+        }
+        for (key, rate) in &[
+            ("tag_rate", self.tag_rate),
+            ("mention_rate", self.mention_rate),
+            ("deleted_rate", self.deleted_rate),
+        ] {
+            if !(0.0..=1.0).contains(rate) {
+                bail!("{} must be between 0 and 1", key);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// IDs of generated data, so that load tests can request things which exist.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Manifest {
+    pub seed: u64,
+    pub users: Vec<Uuid>,
+    pub posts: Vec<ManifestPost>,
+    pub follows: usize,
+    pub tags: Vec<&'static str>,
+}
+
+/// A post, and who posted it, since the API's paths need both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ManifestPost {
+    pub user_id: Uuid,
+    pub id: Uuid,
+    pub deleted: bool,
+}
+
+impl Manifest {
+    pub fn new(seed: u64, snapshot: &Snapshot) -> Self {
+        Self {
+            seed,
+            users: snapshot.users.iter().map(|user| user.id).collect(),
+            posts: snapshot
+                .posts
+                .iter()
+                .map(|post| ManifestPost {
+                    user_id: post.user_id,
+                    id: post.id,
+                    deleted: post.is_deleted(),
+                })
+                .collect(),
+            follows: snapshot.follows.len(),
+            tags: TAGS.to_vec(),
+        }
+    }
+}
+
+/// Generate the data `spec` describes.
+pub fn generate(spec: &Spec) -> Snapshot {
+    let mut rng = ChaCha8Rng::seed_from_u64(spec.seed);
+    let end = spec.start + Duration::days(spec.days.into());
+
+    // Users join during the first half of the period, so that they all have time to post.
+    let joined_by = spec.start + Duration::days(spec.days.into()) / 2;
+    let users: Vec<User> = (0..spec.users)
+        .map(|i| User {
+            id: uuid(&mut rng),
+            created_at: between(&mut rng, spec.start, joined_by),
+            deleted_at: None,
+            name: format!("{}_{}", NAMES[i % NAMES.len()], i),
+        })
+        .collect();
+
+    // Popularity is heavy-tailed, and users follow popular users more, so follower counts follow
+    // a power law.
+    let popularity: Vec<f64> = (0..users.len())
+        .map(|_| pareto(&mut rng, spec.follower_exponent))
+        .collect();
+    let pick_followed = WeightedIndex::new(&popularity).expect("popularity is positive");
+    let mut follows = Vec::new();
+    let mut followed_by: Vec<Vec<usize>> = vec![Vec::new(); users.len()];
+    for (reader, followed) in followed_by.iter_mut().enumerate() {
+        let wanted = exponential(&mut rng, spec.follows_per_user).min(users.len() - 1);
+        let mut chosen = HashSet::new();
+        while chosen.len() < wanted {
+            // The most popular users would be picked again and again, so after picking someone
+            // who's already followed, pick anyone instead.
+            let mut poster = rng.sample(&pick_followed);
+            while poster == reader || chosen.contains(&poster) {
+                poster = rng.gen_range(0, users.len());
+            }
+            chosen.insert(poster);
+            followed.push(poster);
+            follows.push(Follow {
+                posts: users[poster].id,
+                reads: users[reader].id,
+            });
+        }
+    }
+
+    let pick_tag = WeightedIndex::new((1..=TAGS.len()).map(|rank| 1.0 / rank as f64))
+        .expect("tag weights are positive");
+    let mut posts = Vec::new();
+    for (author, user) in users.iter().enumerate() {
+        for _ in 0..exponential(&mut rng, spec.posts_per_user) {
+            let created_at = between(&mut rng, user.created_at, end);
+            let deleted_at = if rng.gen_bool(spec.deleted_rate) {
+                Some(between(&mut rng, created_at, end))
+            } else {
+                None
+            };
+            let mut words = text(&mut rng);
+            if rng.gen_bool(spec.mention_rate) {
+                // People mostly mention people they follow.
+                let mentioned = match followed_by[author].choose(&mut rng) {
+                    Some(&followed) => followed,
+                    None => rng.gen_range(0, users.len()),
+                };
+                let at = rng.gen_range(0, words.len() + 1);
+                words.insert(at, format!("@{}", users[mentioned].name));
+            }
+            if rng.gen_bool(spec.tag_rate) {
+                for _ in 0..rng.gen_range(1, 4) {
+                    words.push(format!("#{}", TAGS[rng.sample(&pick_tag)]));
+                }
+            }
+            posts.push(Post {
+                id: uuid(&mut rng),
+                created_at,
+                deleted_at,
+                content: Content::None,
+                text: words.join(" "),
+                user_id: user.id,
+            });
+        }
+    }
+    posts.sort_by_key(|post| post.created_at);
+
+    Snapshot {
+        users,
+        posts,
+        follows,
+    }
+}
+
+/// Write `snapshot` through `db`'s operations, running up to `concurrency` at once. The datastore
+/// assigns new IDs and timestamps, so the manifest lists those instead of the snapshot's.
+pub async fn write_through<D: Datastore>(
+    db: &D,
+    snapshot: Snapshot,
+    seed: u64,
+    concurrency: usize,
+) -> Fallible<Manifest> {
+    let users: Vec<(Uuid, User)> = stream::iter(snapshot.users)
+        .map(|user| async move {
+            let (id, name) = (user.id, user.name);
+            db.new_user(NewUser { name })
+                .await
+                .map(|created| (id, created))
+        })
+        .buffered(concurrency)
+        .try_collect()
+        .await?;
+    let ids: HashMap<_, _> = users.iter().map(|(old, user)| (*old, user.id)).collect();
+    let ids = &ids;
+
+    let follows = snapshot.follows.len();
+    stream::iter(snapshot.follows)
+        .map(|follow| db.follow(ids[&follow.reads], ids[&follow.posts]))
+        .buffered(concurrency)
+        .try_collect::<Vec<()>>()
+        .await?;
+
+    let posts = stream::iter(snapshot.posts)
+        .map(|post| async move {
+            let user_id = ids[&post.user_id];
+            let new_post = NewPost {
+                content: post.content,
+                text: post.text,
+                user_id,
+            };
+            let id = db.new_post(new_post).await?.id;
+            if post.deleted_at.is_some() {
+                db.delete_post(user_id, id).await?;
+            }
+            Fallible::Ok(ManifestPost {
+                user_id,
+                id,
+                deleted: post.deleted_at.is_some(),
+            })
+        })
+        .buffered(concurrency)
+        .try_collect()
+        .await?;
+
+    Ok(Manifest {
+        seed,
+        users: users.into_iter().map(|(_, user)| user.id).collect(),
+        posts,
+        follows,
+        tags: TAGS.to_vec(),
+    })
+}
+
+/// A random version 4 UUID, from the seeded generator rather than the OS.
+fn uuid(rng: &mut ChaCha8Rng) -> Uuid {
+    Builder::from_bytes(rng.gen())
+        .set_variant(Variant::RFC4122)
+        .set_version(Version::Random)
+        .build()
+}
+
+/// A time in [from, to), to the microsecond, like Postgres stores.
+fn between(rng: &mut ChaCha8Rng, from: DateTime<Utc>, to: DateTime<Utc>) -> DateTime<Utc> {
+    let micros = (to - from).num_microseconds().unwrap_or(i64::MAX).max(1);
+    from + Duration::microseconds(rng.gen_range(0, micros))
+}
+
+/// Words of a post, with log-normally distributed length.
+fn text(rng: &mut ChaCha8Rng) -> Vec<String> {
+    // Box-Muller transform, from two uniform samples to a standard normal one.
+    let (u1, u2): (f64, f64) = (1.0 - rng.gen::<f64>(), rng.gen());
+    let normal = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+    let len = (MEDIAN_WORDS.ln() + WORDS_SIGMA * normal).exp().round() as usize;
+    (0..len.clamp(1, MAX_WORDS))
+        .map(|_| WORDS.choose(rng).expect("there are words").to_string())
+        .collect()
+}
+
+/// A sample from a Pareto distribution with minimum 1 and the given exponent.
+fn pareto(rng: &mut ChaCha8Rng, exponent: f64) -> f64 {
+    (1.0 - rng.gen::<f64>()).powf(-1.0 / (exponent - 1.0))
+}
+
+/// A count with an exponential distribution and the given mean.
+fn exponential(rng: &mut ChaCha8Rng, mean: f64) -> usize {
+    (-(1.0 - rng.gen::<f64>()).ln() * mean).round() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::{memory::MemoryStore, postfilters::PostFilters};
+
+    fn spec(seed: u64) -> Spec {
+        toml::from_str(&format!(
+            "seed = {}\nusers = 500\noutput = {{ to = \"bulk\" }}\n",
+            seed
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_generation_is_reproducible() {
+        let snapshot = generate(&spec(7));
+        assert_eq!(snapshot, generate(&spec(7)));
+        assert_ne!(snapshot.users[0].id, generate(&spec(8)).users[0].id);
+    }
+
+    #[test]
+    fn test_generated_data_is_consistent() {
+        let spec = spec(7);
+        let snapshot = generate(&spec);
+        let end = spec.start + Duration::days(spec.days.into());
+        let users: HashMap<_, _> = snapshot.users.iter().map(|u| (u.id, u)).collect();
+        assert_eq!(users.len(), spec.users);
+
+        for post in &snapshot.posts {
+            let author = users[&post.user_id];
+            assert!(author.created_at <= post.created_at && post.created_at < end);
+            if let Some(deleted_at) = post.deleted_at {
+                assert!(post.created_at <= deleted_at && deleted_at < end);
+            }
+            for mention in post.text.split(' ').filter_map(|w| w.strip_prefix('@')) {
+                assert!(
+                    users.values().any(|user| user.name == mention),
+                    "{}",
+                    mention
+                );
+            }
+        }
+        let follows: HashSet<_> = snapshot.follows.iter().collect();
+        assert_eq!(follows.len(), snapshot.follows.len());
+        let follows = snapshot.follows.len() as f64 / spec.users as f64;
+        assert!(
+            (8.0..12.0).contains(&follows),
+            "{} follows per user",
+            follows
+        );
+        assert!(snapshot.follows.iter().all(|f| f.posts != f.reads));
+
+        // Loose bounds, since the data is random, but the same every run.
+        let posts = snapshot.posts.len() as f64 / spec.users as f64;
+        assert!((15.0..25.0).contains(&posts), "{} posts per user", posts);
+        let deleted = snapshot.posts.iter().filter(|p| p.is_deleted()).count();
+        let deleted = deleted as f64 / snapshot.posts.len() as f64;
+        assert!((0.03..0.07).contains(&deleted), "{} deleted", deleted);
+        let tagged = snapshot
+            .posts
+            .iter()
+            .filter(|p| p.text.contains(" #"))
+            .count();
+        let tagged = tagged as f64 / snapshot.posts.len() as f64;
+        assert!((0.25..0.35).contains(&tagged), "{} tagged", tagged);
+    }
+
+    #[test]
+    fn test_followers_follow_a_power_law() {
+        let snapshot = generate(&spec(7));
+        let mut followers: HashMap<_, usize> = HashMap::new();
+        for follow in &snapshot.follows {
+            *followers.entry(follow.posts).or_default() += 1;
+        }
+        let mut counts: Vec<_> = followers.values().copied().collect();
+        counts.sort_unstable();
+        let median = counts[counts.len() / 2];
+        let max = *counts.last().unwrap();
+        assert!(max > median * 10, "max {}, median {}", max, median);
+    }
+
+    #[actix_rt::test]
+    async fn test_write_through() {
+        let mut spec = spec(7);
+        spec.users = 20;
+        let snapshot = generate(&spec);
+        let store = MemoryStore::default();
+        let manifest = write_through(&store, snapshot.clone(), 7, 4).await.unwrap();
+        assert_eq!(manifest.users.len(), 20);
+        assert_eq!(manifest.posts.len(), snapshot.posts.len());
+        assert_eq!(manifest.follows, snapshot.follows.len());
+
+        let post = manifest.posts.iter().find(|post| post.deleted).unwrap();
+        let found = store.find_post(post.user_id, post.id).await.unwrap();
+        assert!(found.unwrap().is_deleted());
+        let listed = store
+            .list_posts(PostFilters {
+                limit: u8::MAX,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), snapshot.posts.len().min(u8::MAX as usize));
+    }
+
+    #[test]
+    fn test_spec_is_checked() {
+        let mut spec = spec(1);
+        assert!(spec.check().is_ok());
+        spec.follower_exponent = 2.0;
+        assert!(spec.check().is_err());
+        spec.follower_exponent = 3.0;
+        spec.deleted_rate = 1.5;
+        assert!(spec.check().is_err());
+        assert!(toml::from_str::<Spec>("seed = 1\nusers = 2\nuser = 3\n").is_err());
+
+        let example = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/deployment/synthetic_data.toml"
+        );
+        let example = Spec::read(Path::new(example)).unwrap();
+        assert_eq!(example.output, Output::Bulk);
+        assert_eq!(example.follower_exponent, follower_exponent());
+    }
+}
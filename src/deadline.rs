@@ -0,0 +1,127 @@
+//! Per-request deadlines. Requests which run past `request_deadline` are cancelled with a 503. The
+//! deadline propagates into the datastore, which sets each query's statement timeout from it, so
+//! that a slow query doesn't keep running (and holding a connection) after its request gave up.
+use crate::metrics::{self, middleware::method_label};
+use crate::reload::LiveSettings;
+use crate::twoface::{Cause, ExternalError, TfError};
+use actix_service::Service;
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    Error,
+};
+use futures::Future;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    /// When the current request must finish by.
+    static DEADLINE: Instant;
+}
+
+/// The current request's deadline, if it has one.
+pub fn current() -> Option<Instant> {
+    DEADLINE.try_with(|deadline| *deadline).ok()
+}
+
+/// Run `f` as if it were handling a request which must finish by `deadline`.
+#[cfg(test)]
+pub async fn scope<F: Future>(deadline: Instant, f: F) -> F::Output {
+    DEADLINE.scope(deadline, f).await
+}
+
+/// The error when a request runs past its deadline. `internal` says what was cancelled.
+pub fn exceeded(internal: anyhow::Error) -> TfError {
+    TfError {
+        internal,
+        external: ExternalError {
+            cause: Cause::ServiceUnavailable,
+            text: "The request took too long, so it was cancelled. Please try again later".into(),
+        },
+    }
+}
+
+/// Middleware (for `App::wrap_fn`) which gives each request a deadline, and cancels it if it's
+/// still running when the deadline passes. Overruns are counted for each route and method.
+pub fn enforce_deadline<S, B>(
+    request: ServiceRequest,
+    srv: &mut S,
+    settings: &LiveSettings,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let budget = Duration::from_millis(settings.request_deadline_ms.load(Ordering::Relaxed));
+    let deadline = Instant::now() + budget;
+    let route = request
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_owned());
+    let method = method_label(request.method());
+    let response = DEADLINE.scope(deadline, srv.call(request));
+    async move {
+        let response = actix_rt::time::timeout(budget, response).await;
+        // The request might have finished just in time, but only because the datastore gave up.
+        if response.is_err() || Instant::now() >= deadline {
+            metrics::HTTP_DEADLINE_OVERRUNS
+                .with_label_values(&[method, &route])
+                .inc();
+        }
+        match response {
+            Ok(response) => response,
+            Err(_) => Err(exceeded(anyhow::anyhow!(
+                "request still running after {}ms",
+                budget.as_millis()
+            ))
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, web, App};
+    use std::sync::atomic::{AtomicU64, AtomicUsize};
+    use std::sync::Arc;
+
+    #[actix_rt::test]
+    async fn test_slow_requests_are_cancelled() {
+        let settings = Arc::new(LiveSettings {
+            max_body_size: AtomicUsize::new(1024),
+            read_your_writes_window: AtomicU64::new(0),
+            request_deadline_ms: AtomicU64::new(50),
+        });
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(move |req, srv| enforce_deadline(req, srv, &settings))
+                .route(
+                    "/deadline_test/{ms}",
+                    web::get().to(|ms: web::Path<u64>| async move {
+                        assert!(current().is_some());
+                        actix_rt::time::delay_for(Duration::from_millis(*ms)).await;
+                        "done"
+                    }),
+                ),
+        )
+        .await;
+        let overruns =
+            metrics::HTTP_DEADLINE_OVERRUNS.with_label_values(&["GET", "/deadline_test/{ms}"]);
+
+        let req = test::TestRequest::get()
+            .uri("/deadline_test/0")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(overruns.get(), 0);
+
+        let req = test::TestRequest::get()
+            .uri("/deadline_test/1000")
+            .to_request();
+        let err = app.call(req).await.err().unwrap();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(overruns.get(), 1);
+        assert!(current().is_none());
+    }
+}
@@ -0,0 +1,126 @@
+//! Health checks for the orchestrator. `/healthz` reports whether the process is up, and `/readyz`
+//! whether it should be sent traffic.
+use crate::datastore::postgres::{PoolState, PostgresStore};
+use crate::shutdown::Drain;
+use actix_web::{http::StatusCode, web, HttpResponse};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long the database has to answer a readiness check.
+const DB_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What readiness depends on.
+pub struct Health {
+    pub drain: Arc<Drain>,
+    /// None if data is stored in memory, so there's no database to check.
+    pub db: Option<PostgresStore>,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    checks: Checks,
+}
+
+#[derive(Serialize)]
+struct Checks {
+    shutdown: Check,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    database: Option<DatabaseCheck>,
+}
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DatabaseCheck {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    latency_ms: u64,
+    pool: PoolState,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/healthz").route(web::get().to(healthz)))
+        .service(web::resource("/readyz").route(web::get().to(readyz)));
+}
+
+/// The process is up, and its HTTP server is responding.
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "ok": true }))
+}
+
+/// The process can serve requests: it isn't shutting down, and the database (if any) is reachable.
+async fn readyz(health: web::Data<Health>) -> HttpResponse {
+    let shutdown = if health.drain.is_draining() {
+        Check {
+            ok: false,
+            error: Some("shutting down".to_owned()),
+        }
+    } else {
+        Check {
+            ok: true,
+            error: None,
+        }
+    };
+
+    let database = match &health.db {
+        Some(db) => {
+            let start = Instant::now();
+            let ping = db.ping(DB_CHECK_TIMEOUT).await;
+            Some(DatabaseCheck {
+                ok: ping.is_ok(),
+                error: ping.err().map(|e| e.to_string()),
+                latency_ms: start.elapsed().as_millis() as u64,
+                pool: db.pool_state(),
+            })
+        }
+        None => None,
+    };
+
+    let ready = shutdown.ok && database.iter().all(|database| database.ok);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    HttpResponse::build(status).json(Readiness {
+        ready,
+        checks: Checks { shutdown, database },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    #[actix_rt::test]
+    async fn test_not_ready_without_database() {
+        let health = web::Data::new(Health {
+            drain: Arc::new(Drain::default()),
+            db: Some(PostgresStore::unreachable()),
+        });
+        let mut app = test::init_service(App::new().app_data(health).configure(configure)).await;
+
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["checks"]["shutdown"]["ok"], true);
+        assert_eq!(body["checks"]["database"]["ok"], false);
+        assert!(body["checks"]["database"]["error"].is_string());
+        assert_eq!(body["checks"]["database"]["pool"]["max_size"], 10);
+    }
+}
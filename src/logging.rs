@@ -0,0 +1,299 @@
+//! Log output. The log filter and format can be changed while the server runs.
+//!
+//! The filter uses `EnvFilter` directives, e.g. `info,onething::datastore=trace`. Besides the
+//! configured filter, a temporary override can be set through the admin API. Overrides always
+//! expire, so verbose logging can't be left on by accident.
+use crate::config::Config;
+use crate::telemetry::otlp::OtlpLayer;
+use chrono::{offset::Utc, DateTime};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{
+    info,
+    span::{Attributes, Id, Record},
+    warn, Event, Subscriber,
+};
+use tracing_subscriber::{
+    filter::EnvFilter,
+    layer::{Context, Layer, SubscriberExt},
+    registry::Registry,
+    reload,
+    util::SubscriberInitExt,
+};
+
+/// Changes log output after it's been set up.
+#[derive(Clone)]
+pub struct LogHandle {
+    filter: reload::Handle<EnvFilter, Registry>,
+    filters: Arc<Mutex<FilterState>>,
+    human_logs: Arc<AtomicBool>,
+}
+
+struct FilterState {
+    /// The filter from config.
+    configured: String,
+    /// A temporary filter which replaces the configured one until it expires.
+    temporary: Option<TemporaryFilter>,
+    /// Incremented for every override, so that an override's expiry timer doesn't remove a later
+    /// override.
+    generation: u64,
+}
+
+#[derive(Clone)]
+struct TemporaryFilter {
+    directives: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Which log filters are in effect.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct FilterReport {
+    pub configured: String,
+    pub active: String,
+    /// When the active filter will revert to the configured one, if it's a temporary override.
+    pub reverts_at: Option<DateTime<Utc>>,
+}
+
+impl LogHandle {
+    fn new(
+        filter: reload::Handle<EnvFilter, Registry>,
+        configured: String,
+        human_logs: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            filter,
+            filters: Arc::new(Mutex::new(FilterState {
+                configured,
+                temporary: None,
+                generation: 0,
+            })),
+            human_logs,
+        }
+    }
+
+    /// Switch between human-friendly and JSON logs.
+    pub fn set_human_logs(&self, human_logs: bool) {
+        self.human_logs.store(human_logs, Ordering::Relaxed);
+    }
+
+    /// Change the configured filter, e.g. when config is reloaded. If a temporary override is in
+    /// effect, it stays in effect until it expires.
+    pub fn set_configured_filter(&self, directives: &str) -> Result<(), anyhow::Error> {
+        let mut filters = self.lock();
+        if filters.temporary.is_none() {
+            self.apply(directives)?;
+        }
+        filters.configured = directives.to_owned();
+        Ok(())
+    }
+
+    /// Override the configured filter until `revert_after` has passed. Must be called from within
+    /// the Actix runtime, which runs the timer.
+    pub fn set_temporary_filter(
+        &self,
+        directives: &str,
+        revert_after: Duration,
+    ) -> Result<FilterReport, anyhow::Error> {
+        let mut filters = self.lock();
+        self.apply(directives)?;
+        filters.generation += 1;
+        filters.temporary = Some(TemporaryFilter {
+            directives: directives.to_owned(),
+            expires_at: Utc::now() + chrono::Duration::from_std(revert_after)?,
+        });
+        info!(
+            filter = directives,
+            revert_after_secs = revert_after.as_secs(),
+            "temporarily changed log filter"
+        );
+
+        let generation = filters.generation;
+        let handle = self.clone();
+        actix_rt::spawn(async move {
+            actix_rt::time::delay_for(revert_after).await;
+            handle.revert(Some(generation));
+        });
+        Ok(report(&filters))
+    }
+
+    /// Remove the temporary override, if any. If `generation` is given, only remove the override
+    /// if it's still the one from that generation.
+    pub fn revert(&self, generation: Option<u64>) -> FilterReport {
+        let mut filters = self.lock();
+        let is_current = generation.is_none() || generation == Some(filters.generation);
+        if filters.temporary.is_some() && is_current {
+            filters.temporary = None;
+            match self.apply(&filters.configured) {
+                Ok(()) => info!(filter = &filters.configured[..], "reverted log filter"),
+                Err(e) => warn!(error = %e, "couldn't revert log filter"),
+            }
+        }
+        report(&filters)
+    }
+
+    pub fn report(&self) -> FilterReport {
+        report(&self.lock())
+    }
+
+    fn apply(&self, directives: &str) -> Result<(), anyhow::Error> {
+        self.filter.reload(EnvFilter::try_new(directives)?)?;
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, FilterState> {
+        self.filters.lock().expect("log filter lock poisoned")
+    }
+}
+
+fn report(filters: &FilterState) -> FilterReport {
+    let temporary = filters.temporary.clone();
+    FilterReport {
+        configured: filters.configured.clone(),
+        active: temporary
+            .as_ref()
+            .map_or_else(|| filters.configured.clone(), |t| t.directives.clone()),
+        reverts_at: temporary.map(|t| t.expires_at),
+    }
+}
+
+/// Set up the global log output, as configured. Spans are also sent to `otlp_layer` for export.
+pub fn init(config: &Config, otlp_layer: OtlpLayer) -> LogHandle {
+    let filter = EnvFilter::try_new(&config.log_filter)
+        .expect("log_filter is checked when config is loaded");
+    let (filter, filter_handle) = reload::Layer::new(filter);
+    let human_logs = Arc::new(AtomicBool::new(config.human_logs));
+    let format = SwitchFormat {
+        human: tracing_subscriber::fmt::layer(),
+        json: tracing_subscriber::fmt::layer().json(),
+        use_human: Arc::clone(&human_logs),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(format)
+        .with(otlp_layer)
+        .init();
+    LogHandle::new(filter_handle, config.log_filter.clone(), human_logs)
+}
+
+/// Set up the global log output for a one-off command. Logs go to stderr, so they don't mix with
+/// the command's output, and can't be changed once set up.
+pub fn init_for_command(config: &Config) {
+    let filter = EnvFilter::try_new(&config.log_filter)
+        .expect("log_filter is checked when config is loaded");
+    let format = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let registry = tracing_subscriber::registry().with(filter);
+    if config.human_logs {
+        registry.with(format).init();
+    } else {
+        registry.with(format.json()).init();
+    }
+}
+
+/// Formats events with one of two layers, depending on a switch which can be flipped at runtime.
+/// Both layers see every span, so either can format the current span context.
+struct SwitchFormat<H, J> {
+    human: H,
+    json: J,
+    use_human: Arc<AtomicBool>,
+}
+
+impl<S, H, J> Layer<S> for SwitchFormat<H, J>
+where
+    S: Subscriber,
+    H: Layer<S>,
+    J: Layer<S>,
+{
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        self.human.new_span(attrs, id, ctx.clone());
+        self.json.new_span(attrs, id, ctx);
+    }
+
+    fn on_record(&self, span: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        self.human.on_record(span, values, ctx.clone());
+        self.json.on_record(span, values, ctx);
+    }
+
+    fn on_follows_from(&self, span: &Id, follows: &Id, ctx: Context<'_, S>) {
+        self.human.on_follows_from(span, follows, ctx.clone());
+        self.json.on_follows_from(span, follows, ctx);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if self.use_human.load(Ordering::Relaxed) {
+            self.human.on_event(event, ctx);
+        } else {
+            self.json.on_event(event, ctx);
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        self.human.on_enter(id, ctx.clone());
+        self.json.on_enter(id, ctx);
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        self.human.on_exit(id, ctx.clone());
+        self.json.on_exit(id, ctx);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        self.human.on_close(id.clone(), ctx.clone());
+        self.json.on_close(id, ctx);
+    }
+
+    fn on_id_change(&self, old: &Id, new: &Id, ctx: Context<'_, S>) {
+        self.human.on_id_change(old, new, ctx.clone());
+        self.json.on_id_change(old, new, ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_temporary_filter_reverts() {
+        let (_layer, filter) = reload::Layer::<_, Registry>::new(EnvFilter::new("info"));
+        let handle = LogHandle::new(filter, "info".to_owned(), Arc::new(AtomicBool::new(true)));
+
+        assert!(handle
+            .set_temporary_filter("info,onething::datastore=[", Duration::from_secs(1))
+            .is_err());
+        let report = handle
+            .set_temporary_filter("info,onething::datastore=trace", Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(report.active, "info,onething::datastore=trace");
+        assert!(report.reverts_at.is_some());
+
+        // A config reload doesn't end the override, but it changes what will be reverted to.
+        handle.set_configured_filter("warn").unwrap();
+        assert_eq!(handle.report().active, "info,onething::datastore=trace");
+
+        actix_rt::time::delay_for(Duration::from_millis(200)).await;
+        assert_eq!(
+            handle.report(),
+            FilterReport {
+                configured: "warn".to_owned(),
+                active: "warn".to_owned(),
+                reverts_at: None,
+            }
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_old_timer_doesnt_revert_new_override() {
+        let (_layer, filter) = reload::Layer::<_, Registry>::new(EnvFilter::new("info"));
+        let handle = LogHandle::new(filter, "info".to_owned(), Arc::new(AtomicBool::new(true)));
+        handle
+            .set_temporary_filter("debug", Duration::from_millis(50))
+            .unwrap();
+        handle
+            .set_temporary_filter("trace", Duration::from_secs(60))
+            .unwrap();
+        actix_rt::time::delay_for(Duration::from_millis(200)).await;
+        assert_eq!(handle.report().active, "trace");
+        assert_eq!(handle.revert(None).active, "info");
+    }
+}
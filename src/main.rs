@@ -1,7 +1,14 @@
 mod api;
+mod commands;
 mod config;
 mod datastore;
+mod deadline;
+mod health;
+mod logging;
 mod metrics;
+mod reload;
+mod shutdown;
+mod telemetry;
 mod twoface;
 
 #[macro_use]
@@ -13,55 +20,146 @@ extern crate guard;
 #[macro_use]
 extern crate diesel;
 
-use crate::config::Config;
-use crate::datastore::postgres::PostgresStore;
-use actix_service::Service;
-use actix_web::{dev::ServiceResponse, middleware, web, App, HttpServer};
+use crate::config::{Config, Storage};
+use crate::datastore::{memory::MemoryStore, postgres::PostgresStore};
+use actix_service::{Service, ServiceFactory};
+use actix_web::{
+    body::Body,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware, web, App, HttpServer,
+};
 use datastore::postgres;
 use futures::future::FutureExt;
 use std::sync::Arc;
 use std::time::Duration;
-use tracing::{info, warn, Level};
+use telemetry::otlp::{self, OtlpLayer};
+use tracing::{info, warn};
 
 #[allow(clippy::cognitive_complexity)]
 fn main() {
-    let args: Vec<_> = std::env::args().collect();
-    guard!(let [_, config_file_path, ..] = &args[..] else {
-        eprintln!("First argument should be path to config file");
-        return
-    });
+    let args = match config::Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", config::USAGE);
+            std::process::exit(2);
+        }
+    };
+    let config = match Config::load(&args.source) {
+        Ok(config) => config,
+        Err(problems) => {
+            eprintln!("Invalid config:\n{}", problems);
+            std::process::exit(1);
+        }
+    };
+    if args.print_config {
+        match config.redacted() {
+            Ok(printed) => print!("{}", printed),
+            Err(e) => eprintln!("Couldn't print config: {}", e),
+        }
+        return;
+    }
+    match args.command {
+        config::Command::Serve => {}
+        config::Command::CheckConfig => {
+            println!("Config is valid");
+            return;
+        }
+        command => {
+            logging::init_for_command(&config);
+            warn_about_ignored_env_vars();
+            if let Err(e) = commands::run(command, &config) {
+                eprintln!("Error: {:#}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
 
-    let config = Config::from_file(config_file_path);
+    // Set up trace export. It's off unless a collector is configured.
+    let (otlp_layer, otlp_spans) = match config.otlp_endpoint {
+        Some(_) => {
+            let (layer, spans) = OtlpLayer::new();
+            (layer, Some(spans))
+        }
+        None => (OtlpLayer::disabled(), None),
+    };
 
     // Set up logger output
-    let subscriber_builder = tracing_subscriber::fmt().with_max_level(Level::DEBUG);
-    if config.human_logs {
-        subscriber_builder.init();
-    } else {
-        subscriber_builder.json().init();
-    }
+    let log_handle = logging::init(&config, otlp_layer);
 
     info!("starting onething");
+    warn_about_ignored_env_vars();
 
     let sys = actix_rt::System::new("onething");
 
-    // Build the postgres client
-    let db = PostgresStore::new(
-        postgres::Dsn::new(&config),
-        config.db_pool_size,
-        Duration::from_secs(config.db_connection_timeout),
-    )
-    .expect("couldn't connect to Postgres");
-    prometheus::register(Box::new(db.clone())).expect("couldn't register DB metrics");
+    if let (Some(spans), Some(endpoint)) = (otlp_spans, config.otlp_endpoint.clone()) {
+        info!(endpoint = &endpoint[..], "exporting trace spans");
+        actix_rt::spawn(otlp::export_spans(spans, endpoint));
+    }
+
+    // Some settings can be changed by reloading config, without a restart
+    let live_settings = Arc::new(reload::LiveSettings::new(&config));
+    let reloader = Arc::new(reload::Reloader::new(
+        args.source.clone(),
+        config.clone(),
+        Arc::clone(&live_settings),
+        log_handle.clone(),
+    ));
+    actix_rt::spawn(reload::reload_on_sighup(Arc::clone(&reloader)));
 
-    // Build the userfacing app state
-    let db_pointer = Arc::new(db);
     let disable_auth = config.disable_auth;
     if disable_auth {
         warn!("Auth is disabled. This should only happen in testing.");
     }
-    let state = api::Database {
-        ds: Arc::clone(&db_pointer),
+
+    // Build the datastore. Handlers are generic over it, so its routes are chosen here too.
+    let (postgres, memory, userfacing_routes, admin_routes) = match config.storage {
+        Storage::Postgres => {
+            let db = start_postgres(&config);
+            let state = api::Database {
+                ds: Arc::new(db.clone()),
+            };
+            let admin_state = state.clone();
+            let userfacing_routes: Routes = Arc::new(move |cfg: &mut web::ServiceConfig| {
+                cfg.data(state.clone()).service(
+                    web::scope("/accounts").configure(api::userfacing::configure::<PostgresStore>),
+                );
+            });
+            let admin_routes: Routes = Arc::new(move |cfg: &mut web::ServiceConfig| {
+                cfg.data(admin_state.clone()).service(
+                    web::scope("/admin").configure(api::admin::configure::<PostgresStore>),
+                );
+            });
+            (Some(db), None, userfacing_routes, admin_routes)
+        }
+        Storage::Memory => {
+            warn!("Data is stored in memory. This should only happen in development.");
+            let store = MemoryStore::open(
+                config.dev_fixtures_file.as_deref(),
+                config.dev_data_file.clone(),
+            )
+            .expect("couldn't open the in-memory datastore");
+            let store = Arc::new(store);
+            actix_rt::spawn(datastore::memory::flush_periodically(Arc::clone(&store)));
+            let state = api::Database {
+                ds: Arc::clone(&store),
+            };
+            let admin_state = state.clone();
+            let userfacing_routes: Routes = Arc::new(move |cfg: &mut web::ServiceConfig| {
+                cfg.data(state.clone()).service(
+                    web::scope("/accounts").configure(api::userfacing::configure::<MemoryStore>),
+                );
+            });
+            let admin_routes: Routes = Arc::new(move |cfg: &mut web::ServiceConfig| {
+                cfg.data(admin_state.clone()).service(
+                    web::scope("/admin")
+                        .configure(api::admin::configure::<MemoryStore>)
+                        .configure(api::admin::configure_dev),
+                );
+            });
+            (None, Some(store), userfacing_routes, admin_routes)
+        }
     };
 
     // Start the userfacing API server
@@ -69,40 +167,181 @@ fn main() {
         addr = &config.userfacing_listen_address[..],
         "starting userfacing API server"
     );
-    let max_body_size = config.max_body_size;
-    HttpServer::new(move || {
+    let drain = Arc::new(shutdown::Drain::default());
+    let app_drain = Arc::clone(&drain);
+    let admin_settings = Arc::clone(&live_settings);
+    let userfacing_server = HttpServer::new(move || {
+        let live_settings = Arc::clone(&live_settings);
+        let consistency_settings = Arc::clone(&live_settings);
+        let deadline_settings = Arc::clone(&live_settings);
+        let drain = Arc::clone(&app_drain);
+        let routes = Arc::clone(&userfacing_routes);
         App::new()
+            // Send reads to the primary for clients which just wrote
+            .wrap_fn(move |request, srv| {
+                api::read_your_writes::read_your_writes(request, srv, &consistency_settings)
+            })
+            // Cancel requests which take too long
+            .wrap_fn(move |request, srv| {
+                deadline::enforce_deadline(request, srv, &deadline_settings)
+            })
             // Middleware for Prometheus
             .wrap_fn(|request, srv| srv.call(request).map(increment_response_metrics))
-            .data(state.clone())
+            .wrap_fn(metrics::middleware::observe_requests)
+            // Count in-flight requests, so shutdown can wait for them
+            .wrap_fn(move |request, srv| shutdown::track_requests(request, srv, &drain))
+            // Join callers' distributed traces
+            .wrap_fn(telemetry::trace_request)
             // enable logger
             .wrap(middleware::Logger::default())
-            // limit size of the payload (global configuration)
-            .data(web::JsonConfig::default().limit(max_body_size))
-            .service(web::scope("/accounts").configure(api::userfacing::configure))
-            .service(web::scope("/admin").configure(api::admin::configure))
+            // limit size of the payload (global configuration). The limit can change at runtime,
+            // so it's checked by middleware instead of the JSON extractor.
+            .wrap_fn(move |request, srv| reload::limit_body_size(request, srv, &live_settings))
+            .data(web::JsonConfig::default().limit(usize::MAX))
+            .configure(move |cfg| routes(cfg))
     })
     .bind(config.userfacing_listen_address.clone())
     .expect("couldn't start userfacing HTTP server")
+    .shutdown_timeout(config.shutdown_timeout)
+    .disable_signals()
+    .run();
+
+    // Start the admin API server. It's separate, so that it can be kept off the public network.
+    info!(
+        addr = &config.admin_listen_address[..],
+        "starting admin API server"
+    );
+    let reloader = web::Data::from(reloader);
+    let log_handle = web::Data::new(log_handle);
+    let app_drain = Arc::clone(&drain);
+    let admin_server = HttpServer::new(move || {
+        let routes = Arc::clone(&admin_routes);
+        admin_app(Arc::clone(&app_drain), Arc::clone(&admin_settings))
+            .app_data(reloader.clone())
+            .app_data(log_handle.clone())
+            // enable logger
+            .wrap(middleware::Logger::default())
+            .configure(move |cfg| routes(cfg))
+    })
+    .bind(config.admin_listen_address.clone())
+    .expect("couldn't start admin HTTP server")
+    .shutdown_timeout(config.shutdown_timeout)
+    .disable_signals()
     .run();
 
     // Start the metrics server
     info!(
         addr = &config.metrics_address[..],
-        "starting metrics server"
+        "starting metrics and health check server"
     );
-    HttpServer::new(|| {
-        App::new().service(
-            web::scope("/metrics")
-                .service(web::resource("/").route(web::get().to(metrics::endpoint::gather)))
-                .service(web::resource("").route(web::get().to(metrics::endpoint::gather))),
-        )
+    let health = web::Data::new(health::Health {
+        drain: Arc::clone(&drain),
+        db: postgres.clone(),
+    });
+    let metrics_auth = web::Data::new(metrics::endpoint::MetricsAuth::new(&config));
+    let metrics_server = HttpServer::new(move || {
+        App::new()
+            .app_data(health.clone())
+            .app_data(metrics_auth.clone())
+            .configure(health::configure)
+            .service(
+                web::scope("/metrics")
+                    .service(web::resource("/").route(web::get().to(metrics::endpoint::gather)))
+                    .service(web::resource("").route(web::get().to(metrics::endpoint::gather))),
+            )
     })
-    .bind(config.metrics_address)
+    .bind(config.metrics_address.clone())
     .expect("couldn't start metrics server")
+    .shutdown_timeout(config.shutdown_timeout)
+    .disable_signals()
     .run();
 
+    // Shut down gracefully on SIGTERM or SIGINT
+    let servers = shutdown::Servers {
+        draining: vec![("userfacing", userfacing_server), ("admin", admin_server)],
+        last: vec![("metrics", metrics_server)],
+    };
+    actix_rt::spawn(shutdown::shutdown_on_signal(
+        servers,
+        drain,
+        postgres,
+        Duration::from_secs(config.shutdown_timeout),
+    ));
+
     sys.run().expect("actix runtime terminated");
+
+    // Save the in-memory datastore's last writes
+    if let Some(store) = memory {
+        store.flush();
+    }
+}
+
+fn warn_about_ignored_env_vars() {
+    for var in config::ignored_env_vars() {
+        warn!(
+            var = &var[..],
+            "ignoring env var, since it isn't a config key"
+        );
+    }
+}
+
+/// The admin API's app, with the same middleware as the userfacing API's, except for what only
+/// makes sense for users (read-your-writes and the body size limit).
+fn admin_app(
+    drain: Arc<shutdown::Drain>,
+    settings: Arc<reload::LiveSettings>,
+) -> App<
+    impl ServiceFactory<
+        Config = (),
+        Request = ServiceRequest,
+        Response = ServiceResponse<Body>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+    Body,
+> {
+    App::new()
+        // Cancel requests which take too long
+        .wrap_fn(move |request, srv| deadline::enforce_deadline(request, srv, &settings))
+        // Middleware for Prometheus
+        .wrap_fn(|request, srv| srv.call(request).map(increment_response_metrics))
+        .wrap_fn(metrics::middleware::observe_requests)
+        // Count in-flight requests, so shutdown can wait for them
+        .wrap_fn(move |request, srv| shutdown::track_requests(request, srv, &drain))
+        // Join callers' distributed traces
+        .wrap_fn(telemetry::trace_request)
+}
+
+/// Configures the routes which use the datastore.
+type Routes = Arc<dyn Fn(&mut web::ServiceConfig) + Send + Sync>;
+
+/// Migrate and check the database as configured, connect to it, and start refreshing the metrics
+/// which come from it.
+fn start_postgres(config: &Config) -> PostgresStore {
+    if config.auto_migrate {
+        postgres::migrations::Migrator::connect(postgres::Dsn::new(config))
+            .and_then(|migrator| migrator.up())
+            .expect("couldn't migrate the database");
+    }
+    postgres::schema::check(postgres::Dsn::new(config), config.on_schema_drift)
+        .expect("couldn't check the database's schema");
+
+    // Build the postgres client
+    let db = PostgresStore::from_config(config).expect("couldn't connect to Postgres");
+    prometheus::register(Box::new(db.clone())).expect("couldn't register DB metrics");
+
+    // Business metrics come from aggregate queries, so they're refreshed in the background
+    let business_metrics_interval = Duration::from_secs(config.business_metrics_interval);
+    let business_metrics = metrics::business::BusinessMetrics::new(business_metrics_interval)
+        .expect("couldn't make business metrics");
+    prometheus::register(Box::new(business_metrics.clone()))
+        .expect("couldn't register business metrics");
+    actix_rt::spawn(metrics::business::refresh_periodically(
+        business_metrics,
+        db.clone(),
+        business_metrics_interval,
+    ));
+    db
 }
 
 /// If response is OK, increment the metrics for HTTP statuses.
@@ -119,3 +358,40 @@ fn increment_response_metrics<E, B>(
         other => other,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use std::sync::atomic::{AtomicU64, AtomicUsize};
+
+    #[actix_rt::test]
+    async fn test_admin_requests_are_observed() {
+        let settings = Arc::new(reload::LiveSettings {
+            max_body_size: AtomicUsize::new(65536),
+            read_your_writes_window: AtomicU64::new(5),
+            request_deadline_ms: AtomicU64::new(10_000),
+        });
+        let state = api::Database {
+            ds: Arc::new(MemoryStore::default()),
+        };
+        let mut app = test::init_service(
+            admin_app(Arc::default(), settings)
+                .data(state)
+                .service(web::scope("/admin").configure(api::admin::configure::<MemoryStore>)),
+        )
+        .await;
+        let requests = || {
+            metrics::HTTP_REQUESTS
+                .with_label_values(&["GET", "/admin/posts", "ok", "none"])
+                .get()
+        };
+        let before = requests();
+        let req = test::TestRequest::get()
+            .uri("/admin/posts?limit=5")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(requests(), before + 1);
+    }
+}
@@ -1,19 +1,39 @@
+pub mod business;
+pub mod endpoint;
+pub mod middleware;
+pub mod openmetrics;
+
 lazy_static! {
 
-    pub static ref HANDLER_SECS: prometheus::HistogramVec = register_histogram_vec!(
-        "quietbackend_handler_secs",
-        "Seconds taken for each response, partitioned by endpoint name",
-        &["endpoint_name"],
-        vec![1.0, 2.0, 4.0, 16.0] // Prometheus buckets
+    pub static ref HTTP_REQUEST_SECS: prometheus::HistogramVec = register_histogram_vec!(
+        "quietbackend_http_request_secs",
+        "Seconds taken to handle each request, partitioned by method and route",
+        &["method", "route"],
+        latency_buckets()
+    )
+    .expect("couldn't make HTTP_REQUEST_SECS");
+
+    pub static ref HTTP_REQUESTS: prometheus::IntCounterVec = register_int_counter_vec!(
+        "quietbackend_http_requests",
+        "How many requests were handled, partitioned by method, route, ok/err and the error's cause",
+        &["method", "route", "result", "cause"]
+    )
+    .expect("couldn't make HTTP_REQUESTS");
+
+    pub static ref HTTP_IN_FLIGHT: prometheus::IntGaugeVec = register_int_gauge_vec!(
+        "quietbackend_http_in_flight",
+        "How many requests are currently being handled, partitioned by method and route",
+        &["method", "route"]
     )
-    .expect("couldn't make HANDLER_SECS");
+    .expect("couldn't make HTTP_IN_FLIGHT");
 
-    pub static ref RESPONSES: prometheus::IntCounterVec = register_int_counter_vec!(
-        "quietbackend_responses",
-        "How many responses of Ok/Err per endpoint",
-        &["endpoint_name", "result"]
+    pub static ref HTTP_RESPONSE_BYTES: prometheus::HistogramVec = register_histogram_vec!(
+        "quietbackend_http_response_bytes",
+        "Size of each response body, partitioned by method and route",
+        &["method", "route"],
+        prometheus::exponential_buckets(64.0, 4.0, 8).expect("size buckets are valid")
     )
-    .expect("couldn't make RESPONSES");
+    .expect("couldn't make HTTP_RESPONSE_BYTES");
 
     pub static ref HTTP_RESPONSES: prometheus::IntCounterVec = register_int_counter_vec!(
         "quietbackend_http_responses",
@@ -21,22 +41,125 @@ lazy_static! {
         &["status"]
     )
     .expect("couldn't make HTTP_RESPONSES");
+
+    pub static ref TRACE_SPANS: prometheus::IntCounterVec = register_int_counter_vec!(
+        "quietbackend_trace_spans",
+        "How many trace spans were exported, failed to export, or dropped because the export queue was full",
+        &["result"]
+    )
+    .expect("couldn't make TRACE_SPANS");
+
+    pub static ref HTTP_DEADLINE_OVERRUNS: prometheus::IntCounterVec = register_int_counter_vec!(
+        "quietbackend_http_deadline_overruns",
+        "How many requests ran past their deadline, partitioned by method and route",
+        &["method", "route"]
+    )
+    .expect("couldn't make HTTP_DEADLINE_OVERRUNS");
+
+    pub static ref CONFIG_RELOADS: prometheus::IntCounterVec = register_int_counter_vec!(
+        "quietbackend_config_reloads",
+        "How many config reloads were applied, rejected because they need a restart, or invalid",
+        &["result"]
+    )
+    .expect("couldn't make CONFIG_RELOADS");
+
+    pub static ref DB_QUEUE_SECS: prometheus::HistogramVec = register_histogram_vec!(
+        "quietbackend_db_queue_secs",
+        "Seconds each datastore operation waited in the queue for a DB thread",
+        &["pool", "operation"],
+        latency_buckets()
+    )
+    .expect("couldn't make DB_QUEUE_SECS");
+
+    pub static ref DB_QUEUE_DEPTH: prometheus::IntGaugeVec = register_int_gauge_vec!(
+        "quietbackend_db_queue_depth",
+        "How many datastore operations are queued waiting for a DB thread, partitioned by pool",
+        &["pool"]
+    )
+    .expect("couldn't make DB_QUEUE_DEPTH");
+
+    pub static ref DB_QUEUE_REJECTIONS: prometheus::IntCounterVec = register_int_counter_vec!(
+        "quietbackend_db_queue_rejections",
+        "How many datastore operations were rejected because the DB queue was full",
+        &["pool"]
+    )
+    .expect("couldn't make DB_QUEUE_REJECTIONS");
+
+    pub static ref DB_POOL_WAIT_SECS: prometheus::HistogramVec = register_histogram_vec!(
+        "quietbackend_db_pool_wait_secs",
+        "Seconds each datastore operation waited to check out a DB connection",
+        &["pool", "operation"],
+        latency_buckets()
+    )
+    .expect("couldn't make DB_POOL_WAIT_SECS");
+
+    pub static ref DB_QUERY_SECS: prometheus::HistogramVec = register_histogram_vec!(
+        "quietbackend_db_query_secs",
+        "Seconds each datastore operation spent running queries",
+        &["pool", "operation"],
+        latency_buckets()
+    )
+    .expect("couldn't make DB_QUERY_SECS");
+
+    pub static ref DB_POOL_TIMEOUTS: prometheus::IntCounterVec = register_int_counter_vec!(
+        "quietbackend_db_pool_timeouts",
+        "How many datastore operations gave up waiting for a DB connection",
+        &["pool", "operation"]
+    )
+    .expect("couldn't make DB_POOL_TIMEOUTS");
+
+    pub static ref DB_ERRORS: prometheus::IntCounterVec = register_int_counter_vec!(
+        "quietbackend_db_errors",
+        "How many datastore operations failed, partitioned by class of error",
+        &["pool", "operation", "class"]
+    )
+    .expect("couldn't make DB_ERRORS");
+
+    pub static ref DB_REPLICA_HEALTHY: prometheus::IntGaugeVec = register_int_gauge_vec!(
+        "quietbackend_db_replica_healthy",
+        "Whether reads are being sent to each read replica (1) or not, since it recently failed (0)",
+        &["pool"]
+    )
+    .expect("couldn't make DB_REPLICA_HEALTHY");
+
+    pub static ref DB_REPLICA_FALLBACKS: prometheus::IntCounterVec = register_int_counter_vec!(
+        "quietbackend_db_replica_fallbacks",
+        "How many reads went to the primary because no replica could serve them, partitioned by why",
+        &["reason"]
+    )
+    .expect("couldn't make DB_REPLICA_FALLBACKS");
+
+    pub static ref DB_RETRIES: prometheus::IntCounterVec = register_int_counter_vec!(
+        "quietbackend_db_retries",
+        "How many times reads were retried after a transient error, partitioned by class of error",
+        &["operation", "class"]
+    )
+    .expect("couldn't make DB_RETRIES");
+
+    pub static ref DB_BREAKER_STATE: prometheus::IntGaugeVec = register_int_gauge_vec!(
+        "quietbackend_db_breaker_state",
+        "State of each DB's circuit breaker: 0 is closed, 1 is open and 2 is half-open",
+        &["pool"]
+    )
+    .expect("couldn't make DB_BREAKER_STATE");
+
+    pub static ref DB_BREAKER_REJECTIONS: prometheus::IntCounterVec = register_int_counter_vec!(
+        "quietbackend_db_breaker_rejections",
+        "How many datastore operations failed fast because the DB's circuit breaker was open",
+        &["pool"]
+    )
+    .expect("couldn't make DB_BREAKER_REJECTIONS");
+
+    pub static ref DB_SLOW_QUERIES: prometheus::IntCounterVec = register_int_counter_vec!(
+        "quietbackend_db_slow_queries",
+        "How many datastore operations' queries took longer than slow_query_threshold_ms",
+        &["pool", "operation"]
+    )
+    .expect("couldn't make DB_SLOW_QUERIES");
 }
 
-pub mod endpoint {
-    use actix_web::{http, HttpRequest, HttpResponse};
-    use prometheus::Encoder;
-
-    pub fn gather(_req: HttpRequest) -> HttpResponse {
-        let encoder = prometheus::TextEncoder::new();
-        let mut buffer = vec![];
-        let metric_families = prometheus::gather();
-        match encoder.encode(&metric_families, &mut buffer) {
-            Ok(()) => HttpResponse::build(http::StatusCode::OK).body(buffer),
-            Err(e) => {
-                let message = format!("{:?}", e);
-                HttpResponse::build(http::StatusCode::INTERNAL_SERVER_ERROR).body(message)
-            }
-        }
-    }
+/// Buckets from 100µs to about 6.5s, for latencies which can be anything from a cached query to a
+/// stalled connection.
+fn latency_buckets() -> Vec<f64> {
+    prometheus::exponential_buckets(0.0001, 2.0, 17).expect("latency buckets are valid")
 }
@@ -0,0 +1,153 @@
+//! Business metrics about posts, users and follows. They come from aggregate queries, which are
+//! too expensive to run on every scrape, so they're refreshed on a schedule and cached.
+use crate::datastore::{postgres::PostgresStore, structs::BusinessStats};
+use chrono::offset::Utc;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    IntGauge, IntGaugeVec, Opts,
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+/// Reports the most recently refreshed business stats. Reports nothing until the first refresh.
+#[derive(Clone)]
+pub struct BusinessMetrics {
+    latest: Arc<Mutex<Option<BusinessStats>>>,
+    posts_created: IntGauge,
+    posts_deleted: IntGauge,
+    active_users: IntGauge,
+    follows: IntGauge,
+    posts_by_content: IntGaugeVec,
+    refreshed_at: IntGauge,
+}
+
+impl BusinessMetrics {
+    pub fn new(interval: Duration) -> Result<Self, anyhow::Error> {
+        let secs = interval.as_secs();
+        Ok(Self {
+            latest: Arc::new(Mutex::new(None)),
+            posts_created: IntGauge::with_opts(Opts::new(
+                "quietbackend_posts_created",
+                &format!("How many posts were created in the last {} seconds", secs),
+            ))?,
+            posts_deleted: IntGauge::with_opts(Opts::new(
+                "quietbackend_posts_deleted",
+                &format!("How many posts were deleted in the last {} seconds", secs),
+            ))?,
+            active_users: IntGauge::with_opts(Opts::new(
+                "quietbackend_active_users",
+                "How many users created a post in the last day",
+            ))?,
+            follows: IntGauge::with_opts(Opts::new(
+                "quietbackend_follows",
+                "How many follows there are in total",
+            ))?,
+            posts_by_content: IntGaugeVec::new(
+                Opts::new(
+                    "quietbackend_posts_by_content",
+                    "How many posts (which haven't been deleted) have each type of content",
+                ),
+                &["content"],
+            )?,
+            refreshed_at: IntGauge::with_opts(Opts::new(
+                "quietbackend_business_metrics_refreshed_timestamp_seconds",
+                "When the business metrics were last refreshed, as a Unix timestamp",
+            ))?,
+        })
+    }
+
+    fn update(&self, stats: BusinessStats) {
+        *self.latest.lock().expect("business metrics lock poisoned") = Some(stats);
+        self.refreshed_at.set(Utc::now().timestamp());
+    }
+}
+
+impl Collector for BusinessMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = self.posts_created.desc();
+        descs.extend(self.posts_deleted.desc());
+        descs.extend(self.active_users.desc());
+        descs.extend(self.follows.desc());
+        descs.extend(self.posts_by_content.desc());
+        descs.extend(self.refreshed_at.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let latest = self.latest.lock().expect("business metrics lock poisoned");
+        let stats = match &*latest {
+            Some(stats) => stats,
+            None => return Vec::new(),
+        };
+        self.posts_created.set(stats.posts_created);
+        self.posts_deleted.set(stats.posts_deleted);
+        self.active_users.set(stats.active_users);
+        self.follows.set(stats.follows);
+        // Reset, so that content types with no posts left aren't reported with stale counts.
+        self.posts_by_content.reset();
+        for (content, count) in &stats.posts_by_content {
+            self.posts_by_content
+                .with_label_values(&[&format!("{:?}", content)])
+                .set(*count);
+        }
+
+        let mut metrics = self.posts_created.collect();
+        metrics.extend(self.posts_deleted.collect());
+        metrics.extend(self.active_users.collect());
+        metrics.extend(self.follows.collect());
+        metrics.extend(self.posts_by_content.collect());
+        metrics.extend(self.refreshed_at.collect());
+        metrics
+    }
+}
+
+/// Refresh the business metrics every `interval`. Runs forever, so it should be spawned onto the
+/// Actix runtime. If a refresh fails, the previous values are kept, and the refresh timestamp shows
+/// they're stale.
+pub async fn refresh_periodically(metrics: BusinessMetrics, db: PostgresStore, interval: Duration) {
+    let mut ticks = actix_rt::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let since = Utc::now()
+            - chrono::Duration::from_std(interval).unwrap_or_else(|_| chrono::Duration::zero());
+        match db.business_stats(since).await {
+            Ok(stats) => metrics.update(stats),
+            Err(e) => warn!(error = %e, "couldn't refresh business metrics"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::structs::Content;
+
+    #[test]
+    fn test_reports_latest_stats() {
+        let metrics = BusinessMetrics::new(Duration::from_secs(60)).unwrap();
+        assert!(metrics.collect().is_empty());
+
+        metrics.update(BusinessStats {
+            posts_created: 3,
+            posts_deleted: 1,
+            active_users: 2,
+            follows: 5,
+            posts_by_content: vec![(Content::None, 7)],
+        });
+        let families = metrics.collect();
+        let value = |name: &str| {
+            let family = families.iter().find(|f| f.get_name() == name).unwrap();
+            let metric = &family.get_metric()[0];
+            (metric.get_gauge().get_value(), metric.get_label().to_vec())
+        };
+        assert_eq!(value("quietbackend_posts_created").0, 3.0);
+        assert_eq!(value("quietbackend_posts_deleted").0, 1.0);
+        assert_eq!(value("quietbackend_active_users").0, 2.0);
+        assert_eq!(value("quietbackend_follows").0, 5.0);
+        let (posts, labels) = value("quietbackend_posts_by_content");
+        assert_eq!(posts, 7.0);
+        assert_eq!(labels[0].get_value(), "None");
+    }
+}
@@ -0,0 +1,192 @@
+//! Serves metrics for scraping, in whichever format the scraper asks for. Access can be limited to
+//! scrapers which authenticate, and large responses are gzipped.
+use crate::config::Config;
+use crate::metrics::openmetrics;
+use actix_web::{
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse,
+};
+use flate2::{write::GzEncoder, Compression};
+use prometheus::Encoder;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+
+/// Responses at least this big are gzipped, if the scraper accepts gzip.
+const GZIP_MIN_BYTES: usize = 4096;
+
+/// Credentials which scrapers must send.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetricsAuth {
+    None,
+    /// `Authorization: Bearer <token>`
+    Bearer(String),
+    /// HTTP basic auth, with `<username>:<password>`
+    Basic(String),
+}
+
+impl MetricsAuth {
+    pub fn new(config: &Config) -> Self {
+        match (&config.metrics_bearer_token, &config.metrics_basic_auth) {
+            (Some(token), _) => MetricsAuth::Bearer(token.clone()),
+            (None, Some(credentials)) => MetricsAuth::Basic(credentials.clone()),
+            (None, None) => MetricsAuth::None,
+        }
+    }
+
+    fn allows(&self, request: &HttpRequest) -> bool {
+        let authorization = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        match self {
+            MetricsAuth::None => true,
+            MetricsAuth::Bearer(token) => authorization
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .is_some_and(|sent| same_secret(sent.as_bytes(), token.as_bytes())),
+            MetricsAuth::Basic(credentials) => authorization
+                .and_then(|value| value.strip_prefix("Basic "))
+                .and_then(|encoded| base64::decode(encoded.trim()).ok())
+                .is_some_and(|sent| same_secret(&sent, credentials.as_bytes())),
+        }
+    }
+
+    fn challenge(&self) -> &'static str {
+        match self {
+            MetricsAuth::Basic(_) => "Basic realm=\"metrics\"",
+            _ => "Bearer realm=\"metrics\"",
+        }
+    }
+}
+
+/// Compare secrets in constant time. Comparing hashes means the time taken doesn't even depend
+/// on the secrets' lengths.
+fn same_secret(sent: &[u8], expected: &[u8]) -> bool {
+    let (sent, expected) = (Sha256::digest(sent), Sha256::digest(expected));
+    sent.iter()
+        .zip(expected.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+pub async fn gather(req: HttpRequest, auth: web::Data<MetricsAuth>) -> HttpResponse {
+    if !auth.allows(&req) {
+        return HttpResponse::Unauthorized()
+            .header(header::WWW_AUTHENTICATE, auth.challenge())
+            .finish();
+    }
+
+    let metric_families = prometheus::gather();
+    let (body, content_type) = if accepts(&req, header::ACCEPT, "application/openmetrics-text") {
+        (
+            openmetrics::encode(&metric_families).into_bytes(),
+            openmetrics::CONTENT_TYPE.to_owned(),
+        )
+    } else {
+        let encoder = prometheus::TextEncoder::new();
+        let mut buffer = vec![];
+        if let Err(e) = encoder.encode(&metric_families, &mut buffer) {
+            let message = format!("{:?}", e);
+            return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).body(message);
+        }
+        (buffer, encoder.format_type().to_owned())
+    };
+
+    let mut response = HttpResponse::Ok();
+    response
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::VARY, "Accept, Accept-Encoding");
+    if body.len() >= GZIP_MIN_BYTES && accepts(&req, header::ACCEPT_ENCODING, "gzip") {
+        if let Ok(compressed) = gzip(&body) {
+            return response
+                .header(header::CONTENT_ENCODING, "gzip")
+                .body(compressed);
+        }
+    }
+    response.body(body)
+}
+
+/// Does the request's `header` list `value`, with a non-zero quality?
+fn accepts(req: &HttpRequest, header: header::HeaderName, value: &str) -> bool {
+    req.headers()
+        .get_all(header)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let matches = parts.next() == Some(value);
+            let rejected = parts.any(|p| p.replace(' ', "") == "q=0");
+            matches && !rejected
+        })
+}
+
+fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[actix_rt::test]
+    async fn test_gather() {
+        // Make sure there's enough to gzip
+        for i in 0..100 {
+            crate::metrics::HTTP_IN_FLIGHT
+                .with_label_values(&["GET", &format!("/gather_test/{}", i)])
+                .set(0);
+        }
+        let auth = web::Data::new(MetricsAuth::Basic("scraper:hunter2".to_owned()));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(auth)
+                .route("/metrics", web::get().to(gather)),
+        )
+        .await;
+        let get = || test::TestRequest::get().uri("/metrics");
+
+        let resp = test::call_service(&mut app, get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let wrong = format!("Basic {}", base64::encode("scraper:hunter3"));
+        let req = get().header(header::AUTHORIZATION, wrong).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let right = format!("Basic {}", base64::encode("scraper:hunter2"));
+        let req = get()
+            .header(header::AUTHORIZATION, right.clone())
+            .header(
+                header::ACCEPT,
+                "application/openmetrics-text;version=1.0.0,text/plain;q=0.5",
+            )
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            openmetrics::CONTENT_TYPE
+        );
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        let body = test::read_body(resp).await;
+        let mut text = String::new();
+        GzDecoder::new(&body[..]).read_to_string(&mut text).unwrap();
+        assert!(text.contains("# TYPE quietbackend_http_in_flight gauge"));
+        assert!(text.ends_with("# EOF\n"));
+
+        let req = get().header(header::AUTHORIZATION, right).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/plain; version=0.0.4"
+        );
+        assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+}
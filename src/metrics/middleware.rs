@@ -0,0 +1,176 @@
+//! Request metrics, labelled by the route pattern which matched (e.g.
+//! `/accounts/{user_id}/posts`) rather than the path, so that every handler is measured without
+//! having to opt in.
+use crate::metrics::{self, openmetrics};
+use crate::telemetry::otlp::current_trace_id;
+use crate::twoface::TfError;
+use actix_service::Service;
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::Method,
+    Error,
+};
+use futures::{Future, FutureExt};
+use prometheus::IntGauge;
+use std::time::Instant;
+
+/// Route label for requests which didn't match any route. Paths are never used as labels, so
+/// scanners requesting random URLs can't create unbounded numbers of time series.
+const UNMATCHED: &str = "unmatched";
+
+/// Middleware (for `App::wrap_fn`) which records latency, outcome, response size and in-flight
+/// requests for each route and method.
+pub fn observe_requests<S, B>(
+    request: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    let route = request
+        .match_pattern()
+        .unwrap_or_else(|| UNMATCHED.to_owned());
+    let method = method_label(request.method());
+    let in_flight = InFlight::start(metrics::HTTP_IN_FLIGHT.with_label_values(&[method, &route]));
+    let start = Instant::now();
+
+    srv.call(request).map(move |response| {
+        drop(in_flight);
+        let labels = [method, &route[..]];
+        openmetrics::observe(
+            &metrics::HTTP_REQUEST_SECS,
+            &labels,
+            start.elapsed().as_secs_f64(),
+            current_trace_id(),
+        );
+        let (result, cause) = match &response {
+            Ok(response) => outcome(response),
+            Err(e) => ("err", cause_label(e)),
+        };
+        metrics::HTTP_REQUESTS
+            .with_label_values(&[method, &route, result, &cause])
+            .inc();
+        if let Ok(response) = &response {
+            // Streamed bodies have no size until they've been sent.
+            if let BodySize::Sized(bytes) = response.response().body().size() {
+                metrics::HTTP_RESPONSE_BYTES
+                    .with_label_values(&labels)
+                    .observe(bytes as f64);
+            }
+        }
+        response
+    })
+}
+
+/// Whether the response was successful, and if not, why.
+fn outcome<B>(response: &ServiceResponse<B>) -> (&'static str, String) {
+    match response.response().error() {
+        Some(e) => ("err", cause_label(e)),
+        None if response.status().is_client_error() || response.status().is_server_error() => {
+            ("err", "other".to_owned())
+        }
+        None => ("ok", "none".to_owned()),
+    }
+}
+
+/// The twoface `Cause` of an error, or "other" if it didn't come from our own code (e.g. the body
+/// couldn't be parsed).
+fn cause_label(err: &Error) -> String {
+    match err.as_error::<TfError>() {
+        Some(e) => e.external.cause.to_string(),
+        None => "other".to_owned(),
+    }
+}
+
+/// Only standard methods are used as labels, so arbitrary methods can't create new time series.
+pub fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::PATCH => "PATCH",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+        _ => "other",
+    }
+}
+
+/// Decrements the in-flight gauge when dropped, even if the request is cancelled.
+struct InFlight(IntGauge);
+
+impl InFlight {
+    fn start(gauge: IntGauge) -> Self {
+        gauge.inc();
+        InFlight(gauge)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twoface::{Cause, Describe, ExternalError, Fallible};
+    use actix_web::{http::StatusCode, test, web, App};
+    use anyhow::anyhow;
+
+    async fn find_widget(id: web::Path<u32>) -> Fallible<String> {
+        if *id == 0 {
+            return Err(anyhow!("widget 0").describe(ExternalError {
+                cause: Cause::NotFound,
+                text: "no such widget".into(),
+            }));
+        }
+        Ok(format!("widget {}", id))
+    }
+
+    #[actix_rt::test]
+    async fn test_labels_by_route() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(observe_requests)
+                .route("/widgets/{id}", web::get().to(find_widget)),
+        )
+        .await;
+        for uri in &[
+            "/widgets/1",
+            "/widgets/2",
+            "/widgets/0",
+            "/nope/1",
+            "/nope/2",
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            test::call_service(&mut app, req).await;
+        }
+        let req = test::TestRequest::with_uri("/widgets/1")
+            .method(Method::from_bytes(b"BREW").unwrap())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let requests = |labels: &[&str]| metrics::HTTP_REQUESTS.with_label_values(labels).get();
+        assert_eq!(requests(&["GET", "/widgets/{id}", "ok", "none"]), 2);
+        assert_eq!(requests(&["GET", "/widgets/{id}", "err", "NotFound"]), 1);
+        assert_eq!(requests(&["GET", UNMATCHED, "err", "other"]), 2);
+        assert_eq!(requests(&["other", "/widgets/{id}", "err", "other"]), 1);
+        assert_eq!(
+            metrics::HTTP_RESPONSE_BYTES
+                .with_label_values(&["GET", "/widgets/{id}"])
+                .get_sample_sum(),
+            16.0 + 36.0
+        );
+        assert_eq!(
+            metrics::HTTP_IN_FLIGHT
+                .with_label_values(&["GET", "/widgets/{id}"])
+                .get(),
+            0
+        );
+    }
+}
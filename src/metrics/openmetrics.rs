@@ -0,0 +1,314 @@
+//! The OpenMetrics text format (https://openmetrics.io), which unlike the Prometheus text format
+//! can attach exemplars to histogram buckets. An exemplar links a bucket to the trace of one request
+//! which landed in it, so a latency spike on a dashboard leads straight to an example trace.
+use crate::telemetry::tracecontext::TraceId;
+use prometheus::{
+    core::Collector,
+    proto::{LabelPair, MetricFamily, MetricType},
+    HistogramVec,
+};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+lazy_static! {
+    static ref EXEMPLARS: Mutex<Exemplars> = Mutex::new(Exemplars::default());
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    /// Seconds since the Unix epoch
+    timestamp: f64,
+}
+
+/// A time series: a metric family, and the labels which pick out one of its metrics.
+type SeriesKey = (String, Vec<(String, String)>);
+
+/// The latest exemplar in each bucket of each histogram.
+#[derive(Default)]
+pub struct Exemplars {
+    /// Upper bounds of each histogram family's buckets, excluding +Inf.
+    bounds: HashMap<String, Vec<f64>>,
+    /// For each series, one slot per bucket, including +Inf.
+    series: HashMap<SeriesKey, Vec<Option<Exemplar>>>,
+}
+
+impl Exemplars {
+    fn record(
+        &mut self,
+        histogram: &HistogramVec,
+        label_values: &[&str],
+        value: f64,
+        trace_id: TraceId,
+    ) {
+        let desc = match histogram.desc().first() {
+            Some(desc) => *desc,
+            None => return,
+        };
+        let family = desc.fq_name.clone();
+        let bounds = self.bounds.entry(family.clone()).or_insert_with(|| {
+            // Every metric in a family has the same buckets, so read them off any one of them.
+            let metric = histogram.with_label_values(label_values).collect();
+            metric
+                .first()
+                .and_then(|family| family.get_metric().first())
+                .map(|m| {
+                    m.get_histogram()
+                        .get_bucket()
+                        .iter()
+                        .map(|b| b.get_upper_bound())
+                        .collect()
+                })
+                .unwrap_or_default()
+        });
+        let bucket = bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(bounds.len());
+        let slots = bounds.len() + 1;
+
+        let mut labels: Vec<_> = desc
+            .variable_labels
+            .iter()
+            .cloned()
+            .zip(label_values.iter().map(|v| v.to_string()))
+            .collect();
+        labels.sort();
+        let exemplars = self
+            .series
+            .entry((family, labels))
+            .or_insert_with(|| vec![None; slots]);
+        if let Some(slot) = exemplars.get_mut(bucket) {
+            *slot = Some(Exemplar {
+                trace_id: trace_id.to_string(),
+                value,
+                timestamp: unix_timestamp(),
+            });
+        }
+    }
+
+    fn get(&self, family: &str, labels: &[LabelPair], bucket: usize) -> Option<&Exemplar> {
+        let labels = labels
+            .iter()
+            .map(|l| (l.get_name().to_owned(), l.get_value().to_owned()))
+            .collect();
+        self.series
+            .get(&(family.to_owned(), labels))
+            .and_then(|exemplars| exemplars.get(bucket))
+            .and_then(Option::as_ref)
+    }
+}
+
+/// Observe `value` in a histogram. If the observation is part of a trace, remember it as the
+/// exemplar for the bucket it lands in.
+pub fn observe(
+    histogram: &HistogramVec,
+    label_values: &[&str],
+    value: f64,
+    trace_id: Option<TraceId>,
+) {
+    histogram.with_label_values(label_values).observe(value);
+    if let Some(trace_id) = trace_id {
+        if let Ok(mut exemplars) = EXEMPLARS.lock() {
+            exemplars.record(histogram, label_values, value, trace_id);
+        }
+    }
+}
+
+/// Encode metrics in the OpenMetrics format, with the exemplars recorded by `observe`.
+pub fn encode(families: &[MetricFamily]) -> String {
+    match EXEMPLARS.lock() {
+        Ok(exemplars) => encode_with(families, &exemplars),
+        Err(_) => encode_with(families, &Exemplars::default()),
+    }
+}
+
+fn encode_with(families: &[MetricFamily], exemplars: &Exemplars) -> String {
+    let mut out = String::new();
+    for family in families {
+        let name = family.get_name();
+        let (name, kind) = match family.get_field_type() {
+            // OpenMetrics counters' samples are suffixed with _total, but their families aren't.
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "# HELP {} {}", name, escape_help(family.get_help()));
+
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    let value = metric.get_counter().get_value();
+                    sample(&mut out, name, "_total", labels, None, value);
+                }
+                MetricType::GAUGE => {
+                    let value = metric.get_gauge().get_value();
+                    sample(&mut out, name, "", labels, None, value);
+                }
+                MetricType::UNTYPED => {
+                    let value = metric.get_untyped().get_value();
+                    sample(&mut out, name, "", labels, None, value);
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let buckets = histogram.get_bucket();
+                    for (i, bucket) in buckets.iter().enumerate() {
+                        let le = ("le", format_float(bucket.get_upper_bound()));
+                        let count = bucket.get_cumulative_count() as f64;
+                        sample(&mut out, name, "_bucket", labels, Some(le), count);
+                        write_exemplar(&mut out, exemplars.get(name, labels, i));
+                    }
+                    let le = ("le", "+Inf".to_owned());
+                    let count = histogram.get_sample_count() as f64;
+                    sample(&mut out, name, "_bucket", labels, Some(le), count);
+                    write_exemplar(&mut out, exemplars.get(name, labels, buckets.len()));
+                    sample(&mut out, name, "_count", labels, None, count);
+                    let sum = histogram.get_sample_sum();
+                    sample(&mut out, name, "_sum", labels, None, sum);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let q = ("quantile", format_float(quantile.get_quantile()));
+                        sample(&mut out, name, "", labels, Some(q), quantile.get_value());
+                    }
+                    let count = summary.get_sample_count() as f64;
+                    sample(&mut out, name, "_count", labels, None, count);
+                    let sum = summary.get_sample_sum();
+                    sample(&mut out, name, "_sum", labels, None, sum);
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Write one sample, without ending the line (so that an exemplar can follow).
+fn sample(
+    out: &mut String,
+    name: &str,
+    suffix: &str,
+    labels: &[LabelPair],
+    extra: Option<(&str, String)>,
+    value: f64,
+) {
+    let _ = write!(out, "{}{}", name, suffix);
+    let pairs = labels
+        .iter()
+        .map(|l| (l.get_name(), l.get_value()))
+        .chain(extra.as_ref().map(|(k, v)| (*k, v.as_str())));
+    let mut first = true;
+    for (key, value) in pairs {
+        out.push(if first { '{' } else { ',' });
+        first = false;
+        let _ = write!(out, "{}=\"{}\"", key, escape_label(value));
+    }
+    if !first {
+        out.push('}');
+    }
+    let _ = write!(out, " {}", format_float(value));
+    if suffix != "_bucket" {
+        out.push('\n');
+    }
+}
+
+fn write_exemplar(out: &mut String, exemplar: Option<&Exemplar>) {
+    if let Some(e) = exemplar {
+        let _ = write!(
+            out,
+            " # {{trace_id=\"{}\"}} {} {}",
+            e.trace_id,
+            format_float(e.value),
+            e.timestamp
+        );
+    }
+    out.push('\n');
+}
+
+fn format_float(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_owned()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_owned()
+    } else if value.is_nan() {
+        "NaN".to_owned()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unix_timestamp() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{HistogramOpts, IntCounterVec, Opts, Registry};
+
+    #[test]
+    fn test_encode() {
+        let registry = Registry::new();
+        let requests =
+            IntCounterVec::new(Opts::new("requests", "How many requests"), &["route"]).unwrap();
+        let latency = HistogramVec::new(
+            HistogramOpts::new("latency_secs", "Request latency").buckets(vec![0.1, 1.0]),
+            &["route"],
+        )
+        .unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+
+        requests.with_label_values(&["/a\"b"]).inc_by(3);
+        let trace_id = TraceId([0xab; 16]);
+        let mut exemplars = Exemplars::default();
+        for value in &[0.05, 0.5, 5.0] {
+            latency.with_label_values(&["/a"]).observe(*value);
+        }
+        exemplars.record(&latency, &["/a"], 0.5, trace_id);
+        exemplars
+            .series
+            .values_mut()
+            .flatten()
+            .flatten()
+            .for_each(|e| e.timestamp = 1600000000.5);
+
+        let expected = r#"# TYPE latency_secs histogram
+# HELP latency_secs Request latency
+latency_secs_bucket{route="/a",le="0.1"} 1
+latency_secs_bucket{route="/a",le="1"} 2 # {trace_id="abababababababababababababababab"} 0.5 1600000000.5
+latency_secs_bucket{route="/a",le="+Inf"} 3
+latency_secs_count{route="/a"} 3
+latency_secs_sum{route="/a"} 5.55
+# TYPE requests counter
+# HELP requests How many requests
+requests_total{route="/a\"b"} 3
+# EOF
+"#;
+        assert_eq!(encode_with(&registry.gather(), &exemplars), expected);
+    }
+}
@@ -0,0 +1,283 @@
+//! Reloading config while the server runs, triggered by SIGHUP or the admin API. Only settings
+//! which are safe to change at runtime are applied. Other changes (e.g. listen addresses or the
+//! DSN) need a restart, so a reload which changes them is rejected.
+use crate::config::{Config, ConfigSource};
+use crate::logging::LogHandle;
+use crate::metrics;
+use actix_rt::signal::unix::{signal, SignalKind};
+use actix_service::Service;
+use actix_web::{
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    http::header,
+    web, Error, HttpMessage,
+};
+use futures::{
+    future::{ready, Either},
+    Future, StreamExt,
+};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use toml::Value;
+use tracing::{info, warn};
+
+/// Config keys which can be changed without a restart.
+const RELOADABLE_KEYS: &[&str] = &[
+    "max_body_size",
+    "human_logs",
+    "log_filter",
+    "read_your_writes_window",
+    "request_deadline",
+];
+
+/// Settings which are read on every request, so that they can change while the server runs.
+#[derive(Debug)]
+pub struct LiveSettings {
+    pub max_body_size: AtomicUsize,
+    /// Seconds
+    pub read_your_writes_window: AtomicU64,
+    pub request_deadline_ms: AtomicU64,
+}
+
+impl LiveSettings {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_body_size: AtomicUsize::new(config.max_body_size),
+            read_your_writes_window: AtomicU64::new(config.read_your_writes_window),
+            request_deadline_ms: AtomicU64::new(config.request_deadline * 1000),
+        }
+    }
+}
+
+/// What happened when config was reloaded.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ReloadOutcome {
+    /// The changed keys were applied.
+    Applied { changed: Vec<String> },
+    /// The new config changed keys which need a restart, so nothing was applied.
+    Rejected { needs_restart: Vec<String> },
+    /// The new config couldn't be loaded, so nothing was applied.
+    Invalid { problems: Vec<String> },
+}
+
+impl ReloadOutcome {
+    fn metric_label(&self) -> &'static str {
+        match self {
+            ReloadOutcome::Applied { .. } => "applied",
+            ReloadOutcome::Rejected { .. } => "rejected",
+            ReloadOutcome::Invalid { .. } => "invalid",
+        }
+    }
+}
+
+/// Reloads config from the same file, env vars and flags it was originally loaded from.
+pub struct Reloader {
+    source: ConfigSource,
+    current: Mutex<Config>,
+    settings: Arc<LiveSettings>,
+    logs: LogHandle,
+}
+
+impl Reloader {
+    pub fn new(
+        source: ConfigSource,
+        config: Config,
+        settings: Arc<LiveSettings>,
+        logs: LogHandle,
+    ) -> Self {
+        Self {
+            source,
+            current: Mutex::new(config),
+            settings,
+            logs,
+        }
+    }
+
+    /// Reload config, and apply it if only runtime-safe settings changed.
+    pub fn reload(&self) -> ReloadOutcome {
+        let outcome = match Config::load(&self.source) {
+            Ok(new) => self.apply(new),
+            Err(problems) => ReloadOutcome::Invalid {
+                problems: problems.0.iter().map(|p| p.to_string()).collect(),
+            },
+        };
+        match &outcome {
+            ReloadOutcome::Applied { changed } => {
+                info!(changed = ?changed, "reloaded config")
+            }
+            ReloadOutcome::Rejected { needs_restart } => {
+                for key in needs_restart {
+                    warn!(
+                        key = &key[..],
+                        "config reload rejected: {} can't be changed without a restart", key
+                    );
+                }
+            }
+            ReloadOutcome::Invalid { problems } => {
+                warn!(problems = ?problems, "config reload rejected: new config is invalid")
+            }
+        }
+        metrics::CONFIG_RELOADS
+            .with_label_values(&[outcome.metric_label()])
+            .inc();
+        outcome
+    }
+
+    fn apply(&self, new: Config) -> ReloadOutcome {
+        let mut current = self.current.lock().expect("config lock poisoned");
+        let changed = changed_keys(&current, &new);
+        let needs_restart: Vec<_> = changed
+            .iter()
+            .filter(|key| !RELOADABLE_KEYS.contains(&key.as_str()))
+            .cloned()
+            .collect();
+        if !needs_restart.is_empty() {
+            return ReloadOutcome::Rejected { needs_restart };
+        }
+
+        self.settings
+            .max_body_size
+            .store(new.max_body_size, Ordering::Relaxed);
+        self.settings
+            .read_your_writes_window
+            .store(new.read_your_writes_window, Ordering::Relaxed);
+        self.settings
+            .request_deadline_ms
+            .store(new.request_deadline * 1000, Ordering::Relaxed);
+        self.logs.set_human_logs(new.human_logs);
+        if new.log_filter != current.log_filter {
+            if let Err(e) = self.logs.set_configured_filter(&new.log_filter) {
+                warn!(error = %e, "couldn't change log filter");
+            }
+        }
+        *current = new;
+        ReloadOutcome::Applied { changed }
+    }
+}
+
+/// Keys whose values differ between the two configs.
+fn changed_keys(old: &Config, new: &Config) -> Vec<String> {
+    let as_table = |config: &Config| match Value::try_from(config) {
+        Ok(Value::Table(table)) => table,
+        _ => unreachable!("Config is always serialized as a table"),
+    };
+    let (old, new) = (as_table(old), as_table(new));
+    let mut keys: Vec<_> = old.keys().chain(new.keys()).cloned().collect();
+    keys.sort();
+    keys.dedup();
+    keys.retain(|key| old.get(key) != new.get(key));
+    keys
+}
+
+/// Reload config every time the process receives SIGHUP. Runs forever, so it should be spawned
+/// onto the Actix runtime.
+pub async fn reload_on_sighup(reloader: Arc<Reloader>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!(error = %e, "couldn't listen for SIGHUP, so config can't be reloaded by signal");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("received SIGHUP, reloading config");
+        // Reloading reads the config file, so it mustn't block the Actix runtime
+        let reloader = Arc::clone(&reloader);
+        if let Err(e) = web::block(move || Ok::<_, ()>(reloader.reload())).await {
+            warn!(error = %e, "config reload didn't finish");
+        }
+    }
+}
+
+/// Middleware (for `App::wrap_fn`) which rejects request bodies bigger than the current
+/// `max_body_size`. Unlike extractor config, this limit can change while the server runs.
+pub fn limit_body_size<S, B>(
+    mut request: ServiceRequest,
+    srv: &mut S,
+    settings: &LiveSettings,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let limit = settings.max_body_size.load(Ordering::Relaxed);
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > limit) {
+        return Either::Left(ready(Err(PayloadError::Overflow.into())));
+    }
+
+    // The body might not have a Content-Length (e.g. chunked bodies), so count bytes as they come.
+    let mut seen = 0;
+    let limited = request.take_payload().map(move |chunk| {
+        let chunk = chunk?;
+        seen += chunk.len();
+        if seen > limit {
+            return Err(PayloadError::Overflow);
+        }
+        Ok(chunk)
+    });
+    request.set_payload(Payload::Stream(Box::pin(limited)));
+    Either::Right(srv.call(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, web, App};
+
+    #[actix_rt::test]
+    async fn test_body_limit_changes_at_runtime() {
+        let settings = Arc::new(LiveSettings {
+            max_body_size: AtomicUsize::new(8),
+            read_your_writes_window: AtomicU64::new(0),
+            request_deadline_ms: AtomicU64::new(1000),
+        });
+        let app_settings = Arc::clone(&settings);
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(move |req, srv| limit_body_size(req, srv, &app_settings))
+                .app_data(web::PayloadConfig::new(usize::MAX))
+                .route("/", web::post().to(|body: web::Bytes| async move { body })),
+        )
+        .await;
+        let post = |body: &'static str| test::TestRequest::post().uri("/").set_payload(body);
+
+        let resp = test::call_service(&mut app, post("too long!").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let resp = test::call_service(&mut app, post("short").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        settings.max_body_size.store(16, Ordering::Relaxed);
+        let resp = test::call_service(&mut app, post("too long!").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_changed_keys() {
+        let old: Config = toml::from_str(
+            r#"
+userfacing_listen_address = "0.0.0.0:8080"
+admin_listen_address = "0.0.0.0:8081"
+metrics_address = "0.0.0.0:9090"
+human_logs = true
+db_dsn = "postgres://localhost/quiet"
+db_pool_size = 4
+db_connection_timeout = 5
+disable_auth = false
+"#,
+        )
+        .unwrap();
+        let new = Config {
+            max_body_size: 1024,
+            db_dsn: "postgres://elsewhere/quiet".to_owned(),
+            ..old.clone()
+        };
+        assert_eq!(changed_keys(&old, &new), vec!["db_dsn", "max_body_size"]);
+        assert!(changed_keys(&old, &old).is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+//! Graceful shutdown. On SIGTERM or SIGINT, readiness starts failing, the servers stop accepting
+//! connections and in-flight requests get a chance to finish before the process exits.
+use crate::datastore::postgres::{Closed, PostgresStore};
+use actix_rt::signal::unix::{signal, SignalKind};
+use actix_service::Service;
+use actix_web::{
+    dev::{Server, ServiceRequest, ServiceResponse},
+    http::ConnectionType,
+    Error,
+};
+use futures::{
+    future::{self, Either},
+    Future, FutureExt,
+};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Whether the process is shutting down, and how many requests it's serving.
+#[derive(Debug, Default)]
+pub struct Drain {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    /// Requests which were dropped before they finished, e.g. because shutdown timed out.
+    abandoned: AtomicUsize,
+}
+
+impl Drain {
+    /// True once shutdown has started. Readiness checks should fail from then on.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    fn start(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Decrements the in-flight count when the request finishes, or is dropped because the server
+/// stopped waiting for it.
+struct InFlight {
+    drain: Arc<Drain>,
+    finished: bool,
+}
+
+impl InFlight {
+    fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.drain.in_flight.fetch_sub(1, Ordering::SeqCst);
+        if !self.finished {
+            self.drain.abandoned.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Middleware (for `App::wrap_fn`) which counts in-flight requests. While draining, responses also
+/// close their connection, so that keep-alive clients reconnect to another instance.
+pub fn track_requests<S, B>(
+    request: ServiceRequest,
+    srv: &mut S,
+    drain: &Arc<Drain>,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    drain.in_flight.fetch_add(1, Ordering::SeqCst);
+    let in_flight = InFlight {
+        drain: Arc::clone(drain),
+        finished: false,
+    };
+    srv.call(request).map(move |response| {
+        let draining = in_flight.drain.is_draining();
+        in_flight.finish();
+        let mut response = response?;
+        if draining {
+            response
+                .response_mut()
+                .head_mut()
+                .set_connection_type(ConnectionType::Close);
+        }
+        Ok(response)
+    })
+}
+
+/// The servers to stop, in the order they're stopped. Servers which report the process's health
+/// (e.g. metrics) should come last, so they keep answering while the others drain.
+pub struct Servers {
+    pub draining: Vec<(&'static str, Server)>,
+    pub last: Vec<(&'static str, Server)>,
+}
+
+/// Wait for SIGTERM or SIGINT, then shut down gracefully and stop the Actix system. Runs until the
+/// process is about to exit, so it should be spawned onto the Actix runtime. The servers should
+/// be built with signal handling disabled, so that this is the only thing handling the signals.
+pub async fn shutdown_on_signal(
+    servers: Servers,
+    drain: Arc<Drain>,
+    db: Option<PostgresStore>,
+    timeout: Duration,
+) {
+    let received = match wait_for_signal().await {
+        Ok(name) => name,
+        Err(e) => {
+            warn!(error = %e, "couldn't listen for shutdown signals, so shutdown won't be graceful");
+            return;
+        }
+    };
+    let start = Instant::now();
+    let deadline = start + timeout;
+    let in_flight_at_start = drain.in_flight();
+    info!(
+        signal = received,
+        in_flight = in_flight_at_start,
+        timeout_secs = timeout.as_secs(),
+        "shutting down"
+    );
+    drain.start();
+
+    // Stop accepting connections, and wait for in-flight requests. Each server gives up on its
+    // requests after its own shutdown timeout.
+    future::join_all(servers.draining.iter().map(|(name, server)| {
+        server
+            .stop(true)
+            .map(move |()| info!(server = *name, "server stopped"))
+    }))
+    .await;
+    let abandoned_requests = drain.abandoned.load(Ordering::SeqCst);
+
+    // Requests which were abandoned might have left queries running on the blocking thread pool.
+    let Closed {
+        closed_connections,
+        busy_connections,
+    } = match db {
+        Some(db) => db.close(deadline).await,
+        None => Closed::default(),
+    };
+    for (name, server) in &servers.last {
+        server.stop(true).await;
+        info!(server = *name, "server stopped");
+    }
+
+    let elapsed_ms = start.elapsed().as_millis() as u64;
+    if abandoned_requests == 0 && busy_connections == 0 {
+        info!(
+            elapsed_ms,
+            in_flight_at_start,
+            closed_connections,
+            "shutdown complete, every in-flight request finished"
+        );
+    } else {
+        warn!(
+            elapsed_ms,
+            in_flight_at_start,
+            abandoned_requests,
+            closed_connections,
+            busy_connections,
+            "shutdown timed out, some requests or queries were cut off"
+        );
+    }
+    actix_rt::System::current().stop();
+}
+
+/// Returns the name of the signal which was received.
+async fn wait_for_signal() -> std::io::Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let received = future::select(
+        terminate.recv().boxed_local(),
+        interrupt.recv().boxed_local(),
+    )
+    .await;
+    Ok(match received {
+        Either::Left(_) => "SIGTERM",
+        Either::Right(_) => "SIGINT",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App};
+
+    #[actix_rt::test]
+    async fn test_track_requests() {
+        let drain = Arc::new(Drain::default());
+        let app_drain = Arc::clone(&drain);
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(move |req, srv| track_requests(req, srv, &app_drain))
+                .route("/", web::get().to(|| async { "ok" })),
+        )
+        .await;
+
+        let resp = test::call_service(&mut app, test::TestRequest::get().to_request()).await;
+        assert!(resp.response().head().keep_alive());
+        assert_eq!(drain.in_flight(), 0);
+
+        drain.start();
+        let resp = test::call_service(&mut app, test::TestRequest::get().to_request()).await;
+        assert!(!resp.response().head().keep_alive());
+
+        // A request which is dropped before it finishes is abandoned
+        let request = app.call(test::TestRequest::get().to_request());
+        assert_eq!(drain.in_flight(), 1);
+        drop(request);
+        assert_eq!(drain.in_flight(), 0);
+        assert_eq!(drain.abandoned.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,62 @@
+//! Distributed tracing. Requests join their caller's trace (via W3C trace context headers), and
+//! spans can be exported to an OpenTelemetry collector.
+pub mod otlp;
+pub mod tracecontext;
+
+use crate::telemetry::tracecontext::TraceContext;
+use actix_service::Service;
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    Error,
+};
+use std::future::Future;
+use tracing::{field, info_span};
+use tracing_futures::Instrument;
+
+/// Middleware (for `App::wrap_fn`) which handles each request inside a server span. If the caller
+/// sent a `traceparent` header, the span continues the caller's trace.
+pub fn trace_request<S, B>(
+    request: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let route = request
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_owned());
+    let span = info_span!(
+        "http_request",
+        otel.name = %format!("{} {}", request.method(), route),
+        otel.kind = "server",
+        otel.status_code = field::Empty,
+        http.method = %request.method(),
+        http.route = %route,
+        http.status_code = field::Empty,
+        traceparent = field::Empty,
+        tracestate = field::Empty,
+    );
+    if let Some(ctx) = TraceContext::from_headers(request.headers()) {
+        span.record("traceparent", &ctx.to_string().as_str());
+        if let Some(tracestate) = &ctx.tracestate {
+            span.record("tracestate", &tracestate.as_str());
+        }
+    }
+
+    let response = srv.call(request).instrument(span.clone());
+    async move {
+        let response = response.await;
+        match &response {
+            Ok(r) => {
+                span.record("http.status_code", &r.status().as_u16());
+                if r.status().is_server_error() {
+                    span.record("otel.status_code", &"ERROR");
+                }
+            }
+            Err(_) => {
+                span.record("otel.status_code", &"ERROR");
+            }
+        }
+        response
+    }
+}
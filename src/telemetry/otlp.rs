@@ -0,0 +1,506 @@
+//! Export `tracing` spans to an OpenTelemetry collector, using OTLP's HTTP/JSON encoding.
+//!
+//! Spans are collected by a `tracing_subscriber` layer and handed to a background task over a
+//! bounded channel. Handlers never wait on the collector: if the channel is full, spans are
+//! dropped and counted in the metrics.
+use crate::metrics;
+use crate::telemetry::tracecontext::{SpanId, TraceContext, TraceId};
+use actix_web::client::Client;
+use anyhow::{anyhow, bail};
+use futures::{
+    channel::mpsc::{channel, Receiver, Sender},
+    StreamExt,
+};
+use serde::Serialize;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    warn, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, Layer},
+    registry::{LookupSpan, Registry},
+};
+
+/// How many finished spans can wait for export before new spans get dropped.
+const QUEUE_CAPACITY: usize = 2048;
+/// Maximum number of spans sent to the collector in one request.
+const MAX_BATCH_SIZE: usize = 512;
+/// How long to wait for a batch to fill up before sending it anyway.
+const BATCH_DELAY: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+const SERVICE_NAME: &str = "quiet-backend";
+
+/// A span which has closed, and is ready to be exported.
+#[derive(Debug, Clone)]
+pub struct FinishedSpan {
+    pub trace_id: TraceId,
+    pub span_id: SpanId,
+    pub parent_id: Option<SpanId>,
+    pub tracestate: Option<String>,
+    pub name: String,
+    pub kind: SpanKind,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(String, AttributeValue)>,
+    pub is_error: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    Internal,
+    Server,
+    Client,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+    Bool(bool),
+}
+
+/// Tracks a span while it's open. Stored in the span's extensions.
+struct OpenSpan {
+    span: FinishedSpan,
+    has_local_parent: bool,
+}
+
+impl OpenSpan {
+    fn new(name: &str, parent: Option<(TraceId, SpanId)>) -> Self {
+        let now = SystemTime::now();
+        Self {
+            span: FinishedSpan {
+                trace_id: parent.map(|p| p.0).unwrap_or_else(TraceId::random),
+                span_id: SpanId::random(),
+                parent_id: parent.map(|p| p.1),
+                tracestate: None,
+                name: name.to_owned(),
+                kind: SpanKind::Internal,
+                start: now,
+                end: now,
+                attributes: Vec::new(),
+                is_error: false,
+            },
+            has_local_parent: parent.is_some(),
+        }
+    }
+
+    fn set_attribute(&mut self, key: &str, value: AttributeValue) {
+        let span = &mut self.span;
+        match (key, value) {
+            // Spans started by a remote caller join the caller's trace.
+            ("traceparent", AttributeValue::String(header)) => {
+                if self.has_local_parent {
+                    return;
+                }
+                if let Some(ctx) = TraceContext::parse_traceparent(&header) {
+                    span.trace_id = ctx.trace_id;
+                    span.parent_id = Some(ctx.parent_id);
+                }
+            }
+            ("tracestate", AttributeValue::String(state)) => span.tracestate = Some(state),
+            ("otel.name", AttributeValue::String(name)) => span.name = name,
+            ("otel.kind", AttributeValue::String(kind)) => {
+                span.kind = match kind.as_str() {
+                    "server" => SpanKind::Server,
+                    "client" => SpanKind::Client,
+                    _ => SpanKind::Internal,
+                }
+            }
+            ("otel.status_code", AttributeValue::String(code)) => span.is_error = code == "ERROR",
+            (key, value) => span.attributes.push((key.to_owned(), value)),
+        }
+    }
+}
+
+impl Visit for OpenSpan {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set_attribute(field.name(), AttributeValue::Int(value))
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set_attribute(field.name(), AttributeValue::Int(value as i64))
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set_attribute(field.name(), AttributeValue::Bool(value))
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set_attribute(field.name(), AttributeValue::String(value.to_owned()))
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set_attribute(field.name(), AttributeValue::String(format!("{:?}", value)))
+    }
+}
+
+/// A `tracing_subscriber` layer which queues every closed span for export.
+pub struct OtlpLayer {
+    /// None if export is disabled.
+    queue: Option<Mutex<Sender<FinishedSpan>>>,
+}
+
+impl OtlpLayer {
+    /// Returns the layer, and the queue of spans it closes, for `export_spans` to consume.
+    pub fn new() -> (Self, Receiver<FinishedSpan>) {
+        let (sender, receiver) = channel(QUEUE_CAPACITY);
+        let layer = Self {
+            queue: Some(Mutex::new(sender)),
+        };
+        (layer, receiver)
+    }
+
+    /// A layer that ignores all spans.
+    pub fn disabled() -> Self {
+        Self { queue: None }
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if self.queue.is_none() {
+            return;
+        }
+        let span = ctx.span(id).expect("new spans are always in the registry");
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<OpenSpan>()
+                .map(|p| (p.span.trace_id, p.span.span_id))
+        });
+        let mut open_span = OpenSpan::new(attrs.metadata().name(), parent);
+        attrs.record(&mut open_span);
+        span.extensions_mut().insert(open_span);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(open_span) = span.extensions_mut().get_mut::<OpenSpan>() {
+                values.record(open_span);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let open_span = ctx
+            .span(&id)
+            .and_then(|span| span.extensions_mut().remove::<OpenSpan>());
+        let (queue, open_span) = match (&self.queue, open_span) {
+            (Some(queue), Some(open_span)) => (queue, open_span),
+            _ => return,
+        };
+        let mut finished = open_span.span;
+        finished.end = SystemTime::now();
+        let sent = match queue.lock() {
+            Ok(mut sender) => sender.try_send(finished).is_ok(),
+            Err(_) => false,
+        };
+        if !sent {
+            metrics::TRACE_SPANS.with_label_values(&["dropped"]).inc();
+        }
+    }
+}
+
+/// The trace which the current span belongs to, if spans are being exported.
+pub fn current_trace_id() -> Option<TraceId> {
+    tracing::Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            let span = registry.span(id)?;
+            let extensions = span.extensions();
+            extensions.get::<OpenSpan>().map(|open| open.span.trace_id)
+        })
+        .flatten()
+}
+
+/// Send queued spans to the collector at `endpoint` (e.g. `http://localhost:4318`) until the
+/// queue closes. Runs forever, so it should be spawned onto the Actix runtime.
+pub async fn export_spans(spans: Receiver<FinishedSpan>, endpoint: String) {
+    let mut spans = spans.fuse();
+    let client = Client::builder().timeout(EXPORT_TIMEOUT).finish();
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    while let Some(first) = spans.next().await {
+        // Wait a little while for more spans, so they can all be sent in one request.
+        let mut batch = vec![first];
+        let deadline = Instant::now() + BATCH_DELAY;
+        while batch.len() < MAX_BATCH_SIZE {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match actix_rt::time::timeout(remaining, spans.next()).await {
+                Ok(Some(span)) => batch.push(span),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        let batch_size = batch.len() as i64;
+        match send_batch(&client, &url, batch).await {
+            Ok(()) => metrics::TRACE_SPANS
+                .with_label_values(&["exported"])
+                .inc_by(batch_size),
+            Err(e) => {
+                warn!(error = %e, spans = batch_size, "couldn't export spans");
+                metrics::TRACE_SPANS
+                    .with_label_values(&["failed"])
+                    .inc_by(batch_size)
+            }
+        }
+    }
+}
+
+async fn send_batch(
+    client: &Client,
+    url: &str,
+    spans: Vec<FinishedSpan>,
+) -> Result<(), anyhow::Error> {
+    let body = ExportTraceServiceRequest::new(&spans);
+    let resp = client
+        .post(url)
+        .send_json(&body)
+        .await
+        .map_err(|e| anyhow!("couldn't reach collector: {}", e))?;
+    if !resp.status().is_success() {
+        bail!("collector responded {}", resp.status());
+    }
+    Ok(())
+}
+
+// The structs below are the OTLP/JSON encoding of the collector's ExportTraceServiceRequest.
+// See https://github.com/open-telemetry/opentelemetry-proto/blob/main/docs/specification.md
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportTraceServiceRequest {
+    resource_spans: Vec<ResourceSpans>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceSpans {
+    resource: Resource,
+    scope_spans: Vec<ScopeSpans>,
+}
+
+#[derive(Serialize)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Serialize)]
+struct ScopeSpans {
+    scope: Scope,
+    spans: Vec<Span>,
+}
+
+#[derive(Serialize)]
+struct Scope {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Span {
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_span_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace_state: Option<String>,
+    name: String,
+    kind: u8,
+    // 64-bit ints are strings in proto3 JSON
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<KeyValue>,
+    status: Status,
+}
+
+#[derive(Serialize)]
+struct Status {
+    code: u8,
+}
+
+#[derive(Serialize)]
+struct KeyValue {
+    key: String,
+    value: AnyValue,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+#[allow(clippy::enum_variant_names)]
+enum AnyValue {
+    StringValue(String),
+    IntValue(String),
+    BoolValue(bool),
+}
+
+impl ExportTraceServiceRequest {
+    fn new(spans: &[FinishedSpan]) -> Self {
+        Self {
+            resource_spans: vec![ResourceSpans {
+                resource: Resource {
+                    attributes: vec![KeyValue::new(
+                        "service.name",
+                        &AttributeValue::String(SERVICE_NAME.to_owned()),
+                    )],
+                },
+                scope_spans: vec![ScopeSpans {
+                    scope: Scope {
+                        name: env!("CARGO_PKG_NAME"),
+                        version: env!("CARGO_PKG_VERSION"),
+                    },
+                    spans: spans.iter().map(Span::from).collect(),
+                }],
+            }],
+        }
+    }
+}
+
+impl KeyValue {
+    fn new(key: &str, value: &AttributeValue) -> Self {
+        let value = match value {
+            AttributeValue::String(s) => AnyValue::StringValue(s.clone()),
+            AttributeValue::Int(i) => AnyValue::IntValue(i.to_string()),
+            AttributeValue::Bool(b) => AnyValue::BoolValue(*b),
+        };
+        Self {
+            key: key.to_owned(),
+            value,
+        }
+    }
+}
+
+impl From<&FinishedSpan> for Span {
+    fn from(span: &FinishedSpan) -> Self {
+        Self {
+            trace_id: span.trace_id.to_string(),
+            span_id: span.span_id.to_string(),
+            parent_span_id: span.parent_id.map(|id| id.to_string()),
+            trace_state: span.tracestate.clone(),
+            name: span.name.clone(),
+            // Numbering from the OTLP SpanKind enum
+            kind: match span.kind {
+                SpanKind::Internal => 1,
+                SpanKind::Server => 2,
+                SpanKind::Client => 3,
+            },
+            start_time_unix_nano: unix_nanos(span.start),
+            end_time_unix_nano: unix_nanos(span.end),
+            attributes: span
+                .attributes
+                .iter()
+                .map(|(k, v)| KeyValue::new(k, v))
+                .collect(),
+            status: Status {
+                code: if span.is_error { 2 } else { 0 },
+            },
+        }
+    }
+}
+
+fn unix_nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpServer};
+    use futures::SinkExt;
+    use std::sync::Arc;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_layer_links_spans() {
+        let (layer, mut spans) = OtlpLayer::new();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let root = info_span!(
+                "request",
+                otel.kind = "server",
+                traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            );
+            let _entered = root.enter();
+            assert_eq!(
+                current_trace_id().unwrap().to_string(),
+                "4bf92f3577b34da6a3ce929d0e0e4736"
+            );
+            info_span!(
+                "db.query",
+                otel.kind = "client",
+                db.operation = "list_posts"
+            )
+            .in_scope(|| {});
+        });
+
+        let child = spans.try_next().unwrap().unwrap();
+        let root = spans.try_next().unwrap().unwrap();
+        assert_eq!(root.kind, SpanKind::Server);
+        assert_eq!(
+            root.trace_id.to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(root.parent_id.unwrap().to_string(), "00f067aa0ba902b7");
+        assert_eq!(child.kind, SpanKind::Client);
+        assert_eq!(child.trace_id, root.trace_id);
+        assert_eq!(child.parent_id, Some(root.span_id));
+        assert_eq!(
+            child.attributes,
+            vec![(
+                "db.operation".to_owned(),
+                AttributeValue::String("list_posts".to_owned())
+            )]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_export_to_collector() {
+        // Stand-in for an OpenTelemetry collector, which remembers what it received.
+        let received = Arc::new(Mutex::new(Vec::<serde_json::Value>::new()));
+        let collector_state = Arc::clone(&received);
+        let collector = HttpServer::new(move || {
+            let received = Arc::clone(&collector_state);
+            App::new().route(
+                "/v1/traces",
+                web::post().to(move |body: web::Json<serde_json::Value>| {
+                    received.lock().unwrap().push(body.into_inner());
+                    async { "" }
+                }),
+            )
+        })
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = collector.addrs()[0];
+        collector.run();
+
+        let (mut sender, receiver) = channel(1);
+        actix_rt::spawn(export_spans(receiver, format!("http://{}", addr)));
+        let mut span = OpenSpan::new("list_posts", None).span;
+        span.kind = SpanKind::Server;
+        sender.send(span.clone()).await.unwrap();
+        sender.close_channel();
+
+        for _ in 0..50 {
+            if !received.lock().unwrap().is_empty() {
+                break;
+            }
+            actix_rt::time::delay_for(Duration::from_millis(100)).await;
+        }
+        let received = received.lock().unwrap();
+        let exported = &received[0]["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(exported["traceId"], span.trace_id.to_string());
+        assert_eq!(exported["name"], "list_posts");
+        assert_eq!(exported["kind"], 2);
+    }
+}
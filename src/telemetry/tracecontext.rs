@@ -0,0 +1,194 @@
+//! Parsing and generating W3C trace context (https://www.w3.org/TR/trace-context/) identifiers.
+use actix_web::http::HeaderMap;
+use nom::{
+    bytes::complete::{tag, take_while_m_n},
+    combinator::{all_consuming, map_res, opt, rest},
+    sequence::{preceded, tuple},
+    IResult,
+};
+use std::fmt;
+use uuid::Uuid;
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+/// Identifies a whole trace, i.e. every span caused by one external request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceId(pub [u8; 16]);
+
+/// Identifies one span within a trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpanId(pub [u8; 8]);
+
+impl TraceId {
+    pub fn random() -> Self {
+        TraceId(*Uuid::new_v4().as_bytes())
+    }
+}
+
+impl SpanId {
+    pub fn random() -> Self {
+        let mut id = [0; 8];
+        id.copy_from_slice(&Uuid::new_v4().as_bytes()[..8]);
+        SpanId(id)
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl fmt::Display for SpanId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+/// The trace a request belongs to, as propagated by the caller's `traceparent` and `tracestate`
+/// headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: TraceId,
+    /// The caller's span, which becomes the parent of the spans this service creates.
+    pub parent_id: SpanId,
+    pub flags: u8,
+    /// Vendor-specific trace data. It's opaque to us, but must be passed along unchanged.
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// Extract the trace context from request headers. Returns None if there's no valid
+    /// `traceparent` header, in which case the request should start a new trace.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let traceparent = headers.get(TRACEPARENT)?.to_str().ok()?;
+        let mut ctx = Self::parse_traceparent(traceparent)?;
+        ctx.tracestate = headers
+            .get(TRACESTATE)
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.trim().to_owned())
+            .filter(|s| !s.is_empty());
+        Some(ctx)
+    }
+
+    /// Parse a `traceparent` header value, e.g.
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    pub fn parse_traceparent(header: &str) -> Option<Self> {
+        let (_, (version, trace_id, parent_id, flags, suffix)) =
+            all_consuming(traceparent)(header.trim()).ok()?;
+        // Version ff is forbidden. Version 00 has no extra fields, but later versions may append
+        // more, which we're supposed to ignore.
+        if version == 0xff || (version == 0 && suffix.is_some()) {
+            return None;
+        }
+        // All-zero IDs are explicitly invalid.
+        if trace_id == [0; 16] || parent_id == [0; 8] {
+            return None;
+        }
+        let mut trace = [0; 16];
+        trace.copy_from_slice(&trace_id);
+        let mut parent = [0; 8];
+        parent.copy_from_slice(&parent_id);
+        Some(Self {
+            trace_id: TraceId(trace),
+            parent_id: SpanId(parent),
+            flags,
+            tracestate: None,
+        })
+    }
+}
+
+impl fmt::Display for TraceContext {
+    /// Formats the context as a `traceparent` header value.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "00-{}-{}-{:02x}",
+            self.trace_id, self.parent_id, self.flags
+        )
+    }
+}
+
+type TraceParentFields<'a> = (u8, Vec<u8>, Vec<u8>, u8, Option<&'a str>);
+
+fn traceparent(input: &str) -> IResult<&str, TraceParentFields<'_>> {
+    tuple((
+        map_res(lower_hex(2), |s| u8::from_str_radix(s, 16)),
+        preceded(tag("-"), map_res(lower_hex(32), hex::decode)),
+        preceded(tag("-"), map_res(lower_hex(16), hex::decode)),
+        preceded(
+            tag("-"),
+            map_res(lower_hex(2), |s| u8::from_str_radix(s, 16)),
+        ),
+        opt(preceded(tag("-"), rest)),
+    ))(input)
+}
+
+/// Exactly `len` lowercase hex digits. Uppercase hex is invalid in trace context headers.
+fn lower_hex<'a>(len: usize) -> impl Fn(&'a str) -> IResult<&'a str, &'a str> {
+    take_while_m_n(len, len, |c: char| {
+        c.is_ascii_digit() || ('a'..='f').contains(&c)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_traceparent() {
+        let ctx = TraceContext::parse_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )
+        .unwrap();
+        assert_eq!(ctx.trace_id.to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(ctx.parent_id.to_string(), "00f067aa0ba902b7");
+        assert_eq!(ctx.flags, 1);
+        assert_eq!(
+            ctx.to_string(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+
+        // Future versions can add fields
+        assert!(TraceContext::parse_traceparent(
+            "cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-what-the-future-holds"
+        )
+        .is_some());
+    }
+
+    #[test]
+    fn test_reject_invalid_traceparent() {
+        for header in &[
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(TraceContext::parse_traceparent(header), None, "{}", header);
+        }
+    }
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(TraceContext::from_headers(&headers), None);
+        headers.insert(
+            TRACEPARENT.parse().unwrap(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00"
+                .parse()
+                .unwrap(),
+        );
+        headers.insert(
+            TRACESTATE.parse().unwrap(),
+            "congo=t61rcWkgMzE".parse().unwrap(),
+        );
+        let ctx = TraceContext::from_headers(&headers).unwrap();
+        assert_eq!(ctx.flags, 0);
+        assert_eq!(ctx.tracestate.as_deref(), Some("congo=t61rcWkgMzE"));
+    }
+}
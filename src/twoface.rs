@@ -43,7 +43,7 @@ mod tests {
         let io_err = std::fs::read("secret-filename-do-not-leak-to-user").unwrap_err();
         let err = io_err.describe(ExternalError {
             cause: Cause::ServerError,
-            text: "An IO error occurred",
+            text: "An IO error occurred".into(),
         });
         assert_eq!(err.to_string(), "ServerError: An IO error occurred");
     }
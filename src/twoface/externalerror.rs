@@ -1,4 +1,5 @@
 use actix_web::http::StatusCode;
+use std::borrow::Cow;
 use std::fmt;
 
 /// Used to create HTTP responses with the given text and status code.
@@ -6,8 +7,9 @@ use std::fmt;
 pub struct ExternalError {
     /// A user-facing explanation of what caused the error.
     pub cause: Cause,
-    /// Error text that will describe the problem to the user.
-    pub text: &'static str,
+    /// Error text that will describe the problem to the user. Usually a literal, but it can be
+    /// built at runtime, e.g. to say where in a query the problem is.
+    pub text: Cow<'static, str>,
 }
 
 /// A user-facing explanation of what caused the error.
@@ -19,6 +21,7 @@ pub enum Cause {
     UserConflict,
     UserInvalidField,
     NotFound,
+    ServiceUnavailable,
 }
 
 impl fmt::Display for Cause {
@@ -39,6 +42,7 @@ impl Into<StatusCode> for Cause {
             Self::UserBadAuth => StatusCode::UNAUTHORIZED,
             Self::UserConflict => StatusCode::CONFLICT,
             Self::NotFound => StatusCode::NOT_FOUND,
+            Self::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 }
@@ -54,7 +58,7 @@ impl Default for ExternalError {
     fn default() -> Self {
         Self {
             cause: Cause::ServerError,
-            text: "Internal server error",
+            text: "Internal server error".into(),
         }
     }
 }
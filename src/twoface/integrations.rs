@@ -48,7 +48,7 @@ mod tests {
             let file = std::fs::read_to_string("secret-filename-do-not-leak-to-user");
             file.describe_err(ExternalError {
                 cause: Cause::ServerError,
-                text: "page not found",
+                text: "page not found".into(),
             })
             .map(web::Json)
         }